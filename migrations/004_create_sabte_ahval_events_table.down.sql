@@ -0,0 +1 @@
+DROP TABLE IF EXISTS sabte_ahval_events;
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS angulak_like_events;
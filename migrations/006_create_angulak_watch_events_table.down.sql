@@ -0,0 +1 @@
+DROP TABLE IF EXISTS angulak_watch_events;
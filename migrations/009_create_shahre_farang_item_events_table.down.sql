@@ -0,0 +1 @@
+DROP TABLE IF EXISTS shahre_farang_item_events;
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS shahre_farang_play_info_events;
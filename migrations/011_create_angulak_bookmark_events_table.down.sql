@@ -0,0 +1 @@
+DROP TABLE IF EXISTS angulak_bookmark_events;
@@ -0,0 +1,9 @@
+ALTER TABLE login_events RESET SETTING non_replicated_deduplication_window;
+ALTER TABLE sabte_ahval_events RESET SETTING non_replicated_deduplication_window;
+ALTER TABLE angulak_like_events RESET SETTING non_replicated_deduplication_window;
+ALTER TABLE angulak_watch_events RESET SETTING non_replicated_deduplication_window;
+ALTER TABLE session_events RESET SETTING non_replicated_deduplication_window;
+ALTER TABLE angulak_comment_events RESET SETTING non_replicated_deduplication_window;
+ALTER TABLE shahre_farang_item_events RESET SETTING non_replicated_deduplication_window;
+ALTER TABLE shahre_farang_play_info_events RESET SETTING non_replicated_deduplication_window;
+ALTER TABLE angulak_bookmark_events RESET SETTING non_replicated_deduplication_window;
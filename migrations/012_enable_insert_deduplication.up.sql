@@ -0,0 +1,11 @@
+-- Lets ClickHouse drop a repeated insert carrying the same
+-- insert_deduplication_token, e.g. a batch redelivered after its acks were lost.
+ALTER TABLE login_events MODIFY SETTING non_replicated_deduplication_window = 1000;
+ALTER TABLE sabte_ahval_events MODIFY SETTING non_replicated_deduplication_window = 1000;
+ALTER TABLE angulak_like_events MODIFY SETTING non_replicated_deduplication_window = 1000;
+ALTER TABLE angulak_watch_events MODIFY SETTING non_replicated_deduplication_window = 1000;
+ALTER TABLE session_events MODIFY SETTING non_replicated_deduplication_window = 1000;
+ALTER TABLE angulak_comment_events MODIFY SETTING non_replicated_deduplication_window = 1000;
+ALTER TABLE shahre_farang_item_events MODIFY SETTING non_replicated_deduplication_window = 1000;
+ALTER TABLE shahre_farang_play_info_events MODIFY SETTING non_replicated_deduplication_window = 1000;
+ALTER TABLE angulak_bookmark_events MODIFY SETTING non_replicated_deduplication_window = 1000;
@@ -0,0 +1,72 @@
+-- Copies every table, so stop the ingester while this runs.
+CREATE TABLE login_events_swap AS login_events
+ENGINE = MergeTree
+ORDER BY (timestamp, event_name)
+SETTINGS non_replicated_deduplication_window = 1000;
+INSERT INTO login_events_swap SELECT * FROM login_events;
+EXCHANGE TABLES login_events AND login_events_swap;
+DROP TABLE login_events_swap;
+
+CREATE TABLE sabte_ahval_events_swap AS sabte_ahval_events
+ENGINE = MergeTree
+ORDER BY (timestamp, event_name)
+SETTINGS non_replicated_deduplication_window = 1000;
+INSERT INTO sabte_ahval_events_swap SELECT * FROM sabte_ahval_events;
+EXCHANGE TABLES sabte_ahval_events AND sabte_ahval_events_swap;
+DROP TABLE sabte_ahval_events_swap;
+
+CREATE TABLE angulak_like_events_swap AS angulak_like_events
+ENGINE = MergeTree
+ORDER BY (timestamp, event_name)
+SETTINGS non_replicated_deduplication_window = 1000;
+INSERT INTO angulak_like_events_swap SELECT * FROM angulak_like_events;
+EXCHANGE TABLES angulak_like_events AND angulak_like_events_swap;
+DROP TABLE angulak_like_events_swap;
+
+CREATE TABLE angulak_watch_events_swap AS angulak_watch_events
+ENGINE = MergeTree
+ORDER BY (timestamp, event_name)
+SETTINGS non_replicated_deduplication_window = 1000;
+INSERT INTO angulak_watch_events_swap SELECT * FROM angulak_watch_events;
+EXCHANGE TABLES angulak_watch_events AND angulak_watch_events_swap;
+DROP TABLE angulak_watch_events_swap;
+
+CREATE TABLE session_events_swap AS session_events
+ENGINE = MergeTree
+ORDER BY (timestamp, event_name)
+SETTINGS non_replicated_deduplication_window = 1000;
+INSERT INTO session_events_swap SELECT * FROM session_events;
+EXCHANGE TABLES session_events AND session_events_swap;
+DROP TABLE session_events_swap;
+
+CREATE TABLE angulak_comment_events_swap AS angulak_comment_events
+ENGINE = MergeTree
+ORDER BY (timestamp, event_name)
+SETTINGS non_replicated_deduplication_window = 1000;
+INSERT INTO angulak_comment_events_swap SELECT * FROM angulak_comment_events;
+EXCHANGE TABLES angulak_comment_events AND angulak_comment_events_swap;
+DROP TABLE angulak_comment_events_swap;
+
+CREATE TABLE shahre_farang_item_events_swap AS shahre_farang_item_events
+ENGINE = MergeTree
+ORDER BY (timestamp, event_name)
+SETTINGS non_replicated_deduplication_window = 1000;
+INSERT INTO shahre_farang_item_events_swap SELECT * FROM shahre_farang_item_events;
+EXCHANGE TABLES shahre_farang_item_events AND shahre_farang_item_events_swap;
+DROP TABLE shahre_farang_item_events_swap;
+
+CREATE TABLE shahre_farang_play_info_events_swap AS shahre_farang_play_info_events
+ENGINE = MergeTree
+ORDER BY (timestamp, event_name)
+SETTINGS non_replicated_deduplication_window = 1000;
+INSERT INTO shahre_farang_play_info_events_swap SELECT * FROM shahre_farang_play_info_events;
+EXCHANGE TABLES shahre_farang_play_info_events AND shahre_farang_play_info_events_swap;
+DROP TABLE shahre_farang_play_info_events_swap;
+
+CREATE TABLE angulak_bookmark_events_swap AS angulak_bookmark_events
+ENGINE = MergeTree
+ORDER BY (timestamp, event_name)
+SETTINGS non_replicated_deduplication_window = 1000;
+INSERT INTO angulak_bookmark_events_swap SELECT * FROM angulak_bookmark_events;
+EXCHANGE TABLES angulak_bookmark_events AND angulak_bookmark_events_swap;
+DROP TABLE angulak_bookmark_events_swap;
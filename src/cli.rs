@@ -0,0 +1,54 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "forghoon",
+    version,
+    about = "NATS JetStream to ClickHouse ingester"
+)]
+pub struct Cli {
+    /// Configuration file layered over the built-in defaults. May be
+    /// repeated (base first, then overlays); defaults to
+    /// `config/default.toml` if it exists.
+    #[arg(long, short, global = true)]
+    pub config: Vec<PathBuf>,
+
+    /// Override a configuration value, e.g. `--set batcher.max_rows=5000`.
+    /// May be repeated; values are parsed as TOML, falling back to a string.
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    pub overrides: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Consume from NATS and insert into ClickHouse (default).
+    Run,
+    /// Load the configuration and exit non-zero if it is invalid.
+    CheckConfig,
+    /// Print the effective configuration with secrets redacted.
+    PrintConfig,
+    /// Print the version and exit.
+    Version,
+    /// Manage the ClickHouse schema from the migrations directory.
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MigrateCommand {
+    /// Apply all pending migrations.
+    Up,
+    /// Roll back the most recently applied migrations.
+    Down {
+        #[arg(default_value_t = 1)]
+        steps: usize,
+    },
+    /// List migrations and whether they are applied.
+    Status,
+}
@@ -1,12 +1,91 @@
 use crate::config;
+use crate::error::ClickHouseError;
+use crate::metrics::METRICS;
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
 use tracing::info;
 
+/// The `FORMAT` of an insert body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertFormat {
+    /// Varint length-delimited messages, one per row.
+    Protobuf,
+    /// Exactly one bare message.
+    ProtobufSingle,
+    JsonEachRow,
+    RowBinary,
+    MsgPack,
+    AvroConfluent,
+}
+
+impl InsertFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InsertFormat::Protobuf => "Protobuf",
+            InsertFormat::ProtobufSingle => "ProtobufSingle",
+            InsertFormat::JsonEachRow => "JSONEachRow",
+            InsertFormat::RowBinary => "RowBinary",
+            InsertFormat::MsgPack => "MsgPack",
+            InsertFormat::AvroConfluent => "AvroConfluent",
+        }
+    }
+
+    /// Written after a payload that does not already end with it, so the
+    /// next one starts a new row.
+    fn row_separator(self) -> &'static [u8] {
+        match self {
+            InsertFormat::JsonEachRow => b"\n",
+            _ => b"",
+        }
+    }
+}
+
+impl From<config::InputFormat> for InsertFormat {
+    fn from(format: config::InputFormat) -> Self {
+        match format {
+            config::InputFormat::Protobuf => InsertFormat::Protobuf,
+            config::InputFormat::JsonEachRow => InsertFormat::JsonEachRow,
+            config::InputFormat::RowBinary => InsertFormat::RowBinary,
+            config::InputFormat::MsgPack => InsertFormat::MsgPack,
+            config::InputFormat::AvroConfluent => InsertFormat::AvroConfluent,
+        }
+    }
+}
+
+/// Where one insert goes and how its body is encoded.
+pub struct InsertTarget<'a> {
+    pub database: Option<&'a str>,
+    pub table: &'a str,
+    pub format: InsertFormat,
+    /// Sent as the `format_schema` setting unless empty.
+    pub format_schema: &'a str,
+    /// Further settings, sent as query parameters.
+    pub settings: &'a BTreeMap<String, String>,
+}
+
+/// Query parameters the client sets itself, which route settings may not
+/// override.
+pub const RESERVED_PARAMS: &[&str] = &[
+    "query",
+    "database",
+    "format_schema",
+    "insert_deduplication_token",
+];
+
+/// Per-request timeout, connection included.
+pub const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Clone)]
 pub struct ClickHouseClient {
     base_url: String,
     db: String,
     user: Option<String>,
     pass: Option<String>,
     http: reqwest::Client,
+    inserts: Arc<Semaphore>,
+    debug: bool,
 }
 
 impl ClickHouseClient {
@@ -18,8 +97,9 @@ impl ClickHouseClient {
         let http = reqwest::Client::builder()
             // .http2_prior_knowledge()
             .pool_idle_timeout(std::time::Duration::from_secs(30))
+            .pool_max_idle_per_host(clickhouse_config.max_idle_conns as usize)
             .connect_timeout(std::time::Duration::from_secs(3))
-            .timeout(std::time::Duration::from_secs(30))
+            .timeout(REQUEST_TIMEOUT)
             .danger_accept_invalid_certs(false)
             .build()
             .expect("reqwest client");
@@ -40,6 +120,10 @@ impl ClickHouseClient {
                 Some(clickhouse_config.password)
             },
             http,
+            inserts: Arc::new(Semaphore::new(
+                clickhouse_config.max_open_conns.max(1) as usize
+            )),
+            debug: clickhouse_config.debug,
         }
     }
 
@@ -48,7 +132,7 @@ impl ClickHouseClient {
         if let Some(u) = &self.user {
             req = req.basic_auth(u, self.pass.clone());
         }
-        let resp = req.send().await?;
+        let resp = record_response(req.send().await)?;
         let status = resp.status();
         let text = resp.text().await.unwrap_or_default();
         if status.is_success() && text.trim() == "Ok." {
@@ -58,36 +142,147 @@ impl ClickHouseClient {
         }
     }
 
-    pub async fn insert_protobuf_batch(
+    /// Runs a single statement against the configured database and returns
+    /// the raw response body.
+    pub async fn query(&self, sql: &str) -> Result<String, ClickHouseError> {
+        let mut req = self
+            .http
+            .post(&self.base_url)
+            .query(&[("database", &self.db)])
+            .body(sql.to_string());
+        if let Some(u) = &self.user {
+            req = req.basic_auth(u, self.pass.clone());
+        }
+        if self.debug {
+            info!("CH query: {}", sql);
+        }
+        let resp = record_response(req.send().await)?;
+        self.log_response(&resp);
+        if resp.status().is_success() {
+            Ok(resp.text().await?)
+        } else {
+            Err(ClickHouseError::from_response(resp).await)
+        }
+    }
+
+    /// Returns `(name, type)` for each column of the table, or an empty list
+    /// if the table does not exist.
+    pub async fn table_columns(
         &self,
+        database: &str,
         table: &str,
-        format_schema: &str,
-        rows: &[Vec<u8>],
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<Vec<(String, String)>, anyhow::Error> {
+        let sql = format!(
+            "SELECT name, type FROM system.columns WHERE database = {} AND table = {} \
+             ORDER BY position FORMAT TabSeparated",
+            quote(database),
+            quote(table)
+        );
+        let text = self.query(&sql).await?;
+        Ok(text
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(name, ty)| (name.to_string(), ty.to_string()))
+            .collect())
+    }
+
+    /// Inserts one body made of `rows`, each given as the chunks it is
+    /// made of, adding the format's row separator where needed.
+    pub async fn insert_batch(
+        &self,
+        target: &InsertTarget<'_>,
+        dedup_token: &str,
+        rows: &[Vec<Bytes>],
+    ) -> Result<(), ClickHouseError> {
         if rows.is_empty() {
             return Ok(());
         }
-        let mut body = Vec::with_capacity(rows.iter().map(|r| r.len()).sum());
-        for r in rows {
-            body.extend_from_slice(r);
+        let separator = Bytes::from_static(target.format.row_separator());
+        let mut chunks: Vec<Bytes> = Vec::with_capacity(rows.len() * 2);
+        for row in rows {
+            chunks.extend(row.iter().filter(|c| !c.is_empty()).cloned());
+            let ends_with_separator = row
+                .iter()
+                .rfind(|c| !c.is_empty())
+                .is_some_and(|c| c.ends_with(&separator));
+            if !separator.is_empty() && !ends_with_separator {
+                chunks.push(separator.clone());
+            }
         }
+        let body_len: usize = chunks.iter().map(Bytes::len).sum();
+        // Streamed chunk by chunk from the payload buffers, which are only
+        // reference-counted here, never concatenated.
+        let body: Vec<Result<Bytes, std::io::Error>> = chunks.iter().cloned().map(Ok).collect();
+        let body = reqwest::Body::wrap_stream(futures::stream::iter(body));
 
-        let query = format!(
-            "INSERT INTO {}.{} FORMAT Protobuf SETTINGS format_schema='{}'",
-            self.db, table, format_schema
+        let mut query = format!(
+            "INSERT INTO {}.{} FORMAT {}",
+            target.database.unwrap_or(&self.db),
+            target.table,
+            target.format.as_str()
         );
+        if !target.format_schema.is_empty() {
+            query.push_str(&format!(
+                " SETTINGS format_schema={}",
+                quote(target.format_schema)
+            ));
+        }
 
-        let mut req = self.http.post(&self.base_url).query(&[("query", query)]);
+        if self.debug {
+            info!(
+                "CH query: {} ({} rows, {} bytes)",
+                query,
+                rows.len(),
+                body_len
+            );
+        }
+        let mut req = self.http.post(&self.base_url).query(&[
+            ("query", query.as_str()),
+            ("insert_deduplication_token", dedup_token),
+        ]);
+        req = req.query(target.settings);
         if let Some(u) = &self.user {
             req = req.basic_auth(u, self.pass.clone());
         }
-        let resp = req.body(body).send().await?;
+        let _permit = self
+            .inserts
+            .acquire()
+            .await
+            .expect("insert semaphore closed");
+        let resp = record_response(req.body(body).send().await)?;
+        self.log_response(&resp);
         if resp.status().is_success() {
             Ok(())
         } else {
-            let status = resp.status();
-            let text = resp.text().await.unwrap_or_default();
-            Err(anyhow::anyhow!("CH insert failed {}: {}", status, text))
+            Err(ClickHouseError::from_response(resp).await)
+        }
+    }
+}
+
+impl ClickHouseClient {
+    fn log_response(&self, resp: &reqwest::Response) {
+        if self.debug {
+            info!("CH response: {} {:?}", resp.status(), resp.headers());
         }
     }
 }
+
+/// Counts the response status (or transport failure) for `/metrics`.
+fn record_response(
+    resp: Result<reqwest::Response, reqwest::Error>,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let status = match &resp {
+        Ok(r) => r.status().as_u16().to_string(),
+        Err(_) => "transport".to_string(),
+    };
+    METRICS
+        .clickhouse_responses
+        .with_label_values(&[status.as_str()])
+        .inc();
+    resp
+}
+
+/// Quotes a string literal for use in a ClickHouse query.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
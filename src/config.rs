@@ -1,23 +1,331 @@
-use async_nats::jetstream::stream;
-use serde::Deserialize;
+use async_nats::jetstream::{consumer, stream};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::Duration;
 
-#[derive(Debug, Clone, Deserialize)]
+mod validate;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AppConfig {
     pub tracing: TracingConfig,
     pub nats: NatsConfig,
     pub clickhouse: ClickHouseConfig,
     pub batcher: BatchConfig,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub schema_check: SchemaCheckConfig,
+    #[serde(default)]
+    pub payload_validation: PayloadValidationConfig,
+    #[serde(default)]
+    pub migrations: MigrationsConfig,
+    #[serde(default)]
+    pub monitoring: MonitoringConfig,
 }
 
+/// Built-in defaults, the lowest configuration layer. Holds nothing
+/// deployment-specific, so that optional sections such as
+/// `nats.dead_letter` stay unset unless a file sets them.
+const DEFAULTS: &str = include_str!("../config/builtin.toml");
+
+/// Read when no `--config` file is given, if present.
+pub const DEFAULT_CONFIG_PATH: &str = "config/default.toml";
+
+/// Environment variables `FORGHOON__SECTION__KEY` override `section.key`.
+pub const ENV_PREFIX: &str = "FORGHOON__";
+
 impl AppConfig {
-    pub fn load_from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let config_str = std::fs::read_to_string(path)?;
-        let config: AppConfig = toml::from_str(&config_str)?;
+    /// Builds the configuration from, in increasing precedence: built-in
+    /// defaults, each file in `paths`, `FORGHOON__*` environment variables,
+    /// and `key=value` overrides. Secret keys can instead be given as
+    /// `<key>_file` naming a file that holds the value. The result is
+    /// validated before it is returned.
+    pub fn load(
+        paths: &[PathBuf],
+        overrides: &[String],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::load_from(paths, std::env::vars(), overrides)
+    }
+
+    /// `load` with the environment given explicitly.
+    fn load_from(
+        paths: &[PathBuf],
+        env: impl IntoIterator<Item = (String, String)>,
+        overrides: &[String],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut layers = Layers::default();
+        layers.merge_toml(DEFAULTS, "built-in defaults")?;
+
+        for path in paths {
+            let config_str = std::fs::read_to_string(path)
+                .map_err(|e| format!("read {}: {}", path.display(), e))?;
+            layers.merge_toml(&config_str, &path.display().to_string())?;
+        }
+
+        let mut env: Vec<(String, String)> = env
+            .into_iter()
+            .filter(|(k, _)| k.starts_with(ENV_PREFIX))
+            .collect();
+        env.sort();
+        for (name, raw) in env {
+            layers.set_raw(&env_key(&name), &raw, &format!("env {}", name))?;
+        }
+
+        for o in overrides {
+            let (key, raw) = o
+                .split_once('=')
+                .ok_or_else(|| format!("override `{}` is not of the form key=value", o))?;
+            layers.set_raw(key.trim(), raw, &format!("--set {}", o))?;
+        }
+
+        layers.resolve_secret_files()?;
+        let config = layers.deserialize()?;
+        config.validate()?;
         Ok(config)
     }
+
+    /// How long rows may wait in the slowest batch: the largest route
+    /// `max_latency_ms`, or `batcher.flush_interval_ms` if that is larger.
+    pub fn longest_flush_latency(&self) -> Duration {
+        let longest = self
+            .routes
+            .iter()
+            .filter_map(|r| r.max_latency_ms)
+            .fold(self.batcher.flush_interval_ms, u64::max);
+        Duration::from_millis(longest)
+    }
+
+    /// The effective configuration as TOML with passwords and other secrets
+    /// replaced.
+    pub fn to_redacted_toml(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut value = toml::Value::try_from(self)?;
+        redact(&mut value);
+        Ok(toml::to_string_pretty(&value)?)
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Maps `FORGHOON__SECTION__KEY` to `section.key`.
+fn env_key(name: &str) -> String {
+    name.strip_prefix(ENV_PREFIX)
+        .unwrap_or(name)
+        .split("__")
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Parses an override as a TOML value, falling back to a plain string. The
+/// target type is not known yet; see `Layers::deserialize`.
+fn parse_value(raw: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("v = {}", raw))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+/// The merged configuration tree plus where each key was last set, so
+/// errors can point at the offending file or variable.
+#[derive(Default)]
+struct Layers {
+    table: toml::Table,
+    origins: HashMap<String, String>,
+    /// Overrides that parsed as something other than a string, kept as
+    /// given in case the key turns out to expect a string.
+    raw: HashMap<String, String>,
+}
+
+impl Layers {
+    fn merge_toml(&mut self, text: &str, origin: &str) -> Result<(), String> {
+        let table: toml::Table = toml::from_str(text).map_err(|e| format!("{}: {}", origin, e))?;
+        for (key, value) in table {
+            self.merge(key, value, origin)?;
+        }
+        Ok(())
+    }
+
+    /// Tables merge key by key; any other value replaces what was there.
+    fn merge(&mut self, key: String, value: toml::Value, origin: &str) -> Result<(), String> {
+        match value {
+            toml::Value::Table(t) => {
+                self.table_at(&key, origin)?;
+                for (k, v) in t {
+                    self.merge(format!("{}.{}", key, k), v, origin)?;
+                }
+                Ok(())
+            }
+            value => self.set(&key, value, origin),
+        }
+    }
+
+    fn table_at(&mut self, key: &str, origin: &str) -> Result<&mut toml::Table, String> {
+        let mut current = &mut self.table;
+        for part in key.split('.') {
+            current = current
+                .entry(part)
+                .or_insert_with(|| toml::Value::Table(Default::default()))
+                .as_table_mut()
+                .ok_or_else(|| format!("{}: `{}` is not a table", origin, key))?;
+        }
+        Ok(current)
+    }
+
+    fn set(&mut self, key: &str, value: toml::Value, origin: &str) -> Result<(), String> {
+        if key.split('.').any(str::is_empty) {
+            return Err(format!("{}: invalid key `{}`", origin, key));
+        }
+        let (table, last) = match key.rsplit_once('.') {
+            Some((parent, last)) => (self.table_at(parent, origin)?, last),
+            None => (&mut self.table, key),
+        };
+        table.insert(last.to_string(), value);
+        self.origins.insert(key.to_string(), origin.to_string());
+        self.raw.remove(key);
+        Ok(())
+    }
+
+    /// Sets `key` from an environment variable or `--set` value.
+    fn set_raw(&mut self, key: &str, raw: &str, origin: &str) -> Result<(), String> {
+        let value = parse_value(raw);
+        let is_str = value.is_str();
+        self.set(key, value, origin)?;
+        if !is_str {
+            self.raw.insert(key.to_string(), raw.to_string());
+        }
+        Ok(())
+    }
+
+    /// Replaces every secret `<key>_file = "path"` with `<key>` set to the
+    /// file's contents, without the trailing newline.
+    fn resolve_secret_files(&mut self) -> Result<(), String> {
+        fn walk(
+            table: &mut toml::Table,
+            prefix: &str,
+            origins: &mut HashMap<String, String>,
+        ) -> Result<(), String> {
+            let file_keys: Vec<String> = table
+                .iter()
+                .filter(|(k, v)| k.strip_suffix("_file").is_some_and(is_secret_key) && v.is_str())
+                .map(|(k, _)| k.clone())
+                .collect();
+            for file_key in file_keys {
+                let full_key = format!("{}{}", prefix, file_key);
+                let path = table.remove(&file_key).unwrap();
+                let path = path.as_str().unwrap_or_default();
+                let secret = std::fs::read_to_string(path).map_err(|e| {
+                    format!(
+                        "invalid value for `{}` (from {}): read {}: {}",
+                        full_key,
+                        origins.get(&full_key).map_or("?", String::as_str),
+                        path,
+                        e
+                    )
+                })?;
+                let key = file_key.trim_end_matches("_file");
+                table.insert(
+                    key.to_string(),
+                    toml::Value::String(secret.trim_end_matches(['\r', '\n']).to_string()),
+                );
+                origins.insert(format!("{}{}", prefix, key), format!("file {}", path));
+            }
+            for (k, v) in table.iter_mut() {
+                if let toml::Value::Table(t) = v {
+                    walk(t, &format!("{}{}.", prefix, k), origins)?;
+                }
+            }
+            Ok(())
+        }
+        walk(&mut self.table, "", &mut self.origins)
+    }
+
+    /// Where the value at `path`, or its closest ancestor, was set.
+    fn origin(&self, path: &str) -> &str {
+        let mut path = path;
+        loop {
+            if let Some(origin) = self.origins.get(path) {
+                return origin;
+            }
+            match path.rfind(['.', '[']) {
+                Some(i) => path = &path[..i],
+                None => return "built-in defaults",
+            }
+        }
+    }
+
+    /// An override such as `PASSWORD=123456` parses as an integer; when the
+    /// key expects a string, it is retried as the string it was given as.
+    fn deserialize(mut self) -> Result<AppConfig, Box<dyn std::error::Error>> {
+        // The error at the last key retried as a string, reported instead
+        // if the string does not fit either.
+        let mut retried: Option<(String, String)> = None;
+        loop {
+            let value = toml::Value::Table(self.table.clone());
+            let e = match serde_path_to_error::deserialize(value) {
+                Ok(config) => return Ok(config),
+                Err(e) => e,
+            };
+            let path = e.path().to_string();
+            let mut message = e.inner().message().to_string();
+            if let Some((retried_path, original)) = retried.take()
+                && retried_path == path
+            {
+                message = original;
+            } else if message.starts_with("invalid type")
+                && let Some(raw) = self.raw.remove(&path)
+            {
+                let origin = self.origin(&path).to_string();
+                self.set(&path, toml::Value::String(raw), &origin)?;
+                retried = Some((path, message));
+                continue;
+            }
+            if let Some(rest) = message.strip_prefix("unknown field `")
+                && let Some((_, expected)) = rest.split_once("`, ")
+            {
+                return Err(format!(
+                    "unknown key `{}` (from {}): {}",
+                    path,
+                    self.origin(&path),
+                    expected
+                )
+                .into());
+            }
+            return Err(format!(
+                "invalid value for `{}` (from {}): {}",
+                path,
+                self.origin(&path),
+                message
+            )
+            .into());
+        }
+    }
+}
+
+fn is_secret_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    ["password", "secret", "token"]
+        .iter()
+        .any(|s| key.contains(s))
+}
+
+fn redact(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(t) => {
+            for (k, v) in t.iter_mut() {
+                if is_secret_key(k) && v.is_str() {
+                    *v = toml::Value::String("<redacted>".to_string());
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        toml::Value::Array(a) => a.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct TracingConfig {
     pub level: String,
     pub format: LogFormat,
@@ -28,33 +336,61 @@ pub struct TracingConfig {
     pub with_file: bool,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum LogFormat {
     Text,
     Json,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct NatsConfig {
     pub client_port: u16,
+    /// NATS HTTP monitoring port, polled for readiness when
+    /// `monitoring.check_nats_server` is set.
     pub server_port: u16,
+    /// Empty to connect without authentication.
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub password: String,
     pub host: String,
-    pub queue: String,
+    /// Deprecated and ignored: pull consumers have no queue groups.
+    /// Instances share the work through `consumer_name` instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue: Option<String>,
+    #[serde(default)]
     pub subjects: Vec<String>,
+    /// Durable pull consumer. Every instance binding to the same name gets
+    /// its own share of the messages. Also the NATS connection name shown
+    /// in monitoring (`/connz`).
     pub consumer_name: String,
+    /// Messages processed concurrently by the consume loop. Defaults to the
+    /// `WORKERS` environment variable, then to 4 per CPU.
+    #[serde(default)]
+    pub workers: Option<usize>,
     pub stream_config: NatsStreamConfig,
+    #[serde(default)]
+    pub consumer: ConsumerConfig,
+    /// Where poison messages are republished before being terminated.
+    /// Unset means they are only terminated.
+    #[serde(default)]
+    pub dead_letter: Option<DeadLetterConfig>,
 }
 
 impl NatsConfig {
     pub fn get_addr(&self) -> String {
         format!("nats://{}:{}", self.host, self.client_port)
     }
+
+    pub fn get_monitoring_addr(&self) -> String {
+        format!("http://{}:{}", self.host, self.server_port)
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct NatsStreamConfig {
     pub name: String,
     #[serde(with = "RetentionPolicyDef")]
@@ -65,18 +401,183 @@ pub struct NatsStreamConfig {
     pub storage: stream::StorageType,
     pub no_ack: bool,
     pub max_consumers: u32,
+    /// Duration such as "24h"; "0s" keeps messages forever.
     pub max_age: String,
+    #[serde(default = "default_replicas")]
+    pub replicas: usize,
+    /// Messages kept before `discard` applies; -1 for no limit.
+    #[serde(default = "default_stream_limit")]
+    pub max_msgs: i64,
+    /// Bytes kept before `discard` applies; -1 for no limit.
+    #[serde(default = "default_stream_limit")]
+    pub max_bytes: i64,
+    /// Window in which a repeated `Nats-Msg-Id` is dropped as a duplicate.
+    #[serde(default = "default_duplicate_window")]
+    pub duplicate_window: String,
+    #[serde(with = "CompressionDef", default = "default_compression")]
+    pub compression: stream::Compression,
+    /// Apply differences between this section and an existing stream
+    /// instead of refusing to start.
+    #[serde(default)]
+    pub update_existing: bool,
 
     need_create: bool,
 }
 
 impl NatsStreamConfig {
+    /// `max_age` as a duration. Validated when the config is loaded.
+    pub fn max_age(&self) -> Duration {
+        humantime::parse_duration(&self.max_age).unwrap_or_default()
+    }
+
+    /// `duplicate_window` as a duration. Validated when the config is loaded.
+    pub fn duplicate_window(&self) -> Duration {
+        humantime::parse_duration(&self.duplicate_window).unwrap_or_default()
+    }
+
+    /// Whether a missing stream is created; otherwise it must already exist.
     pub fn need_create(&self) -> bool {
         self.need_create
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+fn default_replicas() -> usize {
+    1
+}
+
+fn default_stream_limit() -> i64 {
+    -1
+}
+
+fn default_duplicate_window() -> String {
+    "2m".to_string()
+}
+
+fn default_compression() -> stream::Compression {
+    stream::Compression::None
+}
+
+/// Settings of the durable pull consumer. Durations are written like
+/// `max_age`, e.g. "90s" or "2m".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsumerConfig {
+    /// How long a delivered message may stay unacknowledged before it is
+    /// redelivered. Rows held longer, in a batch or a slow flush, get an
+    /// in-progress ack every half of it. At least one second.
+    pub ack_wait: String,
+    /// Unacknowledged messages outstanding at once; -1 for no limit.
+    pub max_ack_pending: i64,
+    /// Upper bound on the bytes returned by a single pull request.
+    pub max_bytes: i64,
+    /// Deliveries before JetStream gives up on a message; -1 for no limit.
+    pub max_deliver: i64,
+    /// Redelivery delays per delivery, replacing `ack_wait` after a timeout.
+    /// Needs fewer entries than `max_deliver`; each at least one second.
+    pub backoff: Vec<String>,
+    pub deliver_policy: DeliverPolicy,
+    /// UTC RFC 3339 timestamp, e.g. "2024-05-01T00:00:00Z"; required by
+    /// `deliver_policy = "by_start_time"`.
+    pub start_time: Option<String>,
+    /// Required by `deliver_policy = "by_start_sequence"`.
+    pub start_sequence: Option<u64>,
+    /// Idle time after which the server removes the consumer; "0s" keeps
+    /// it forever.
+    pub inactive_threshold: String,
+    /// Pull requests the server queues at once.
+    pub max_waiting: i64,
+    #[serde(with = "ReplayPolicyDef")]
+    pub replay_policy: consumer::ReplayPolicy,
+    /// Apply differences between this section and an existing durable
+    /// consumer instead of refusing to start.
+    pub update_existing: bool,
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> Self {
+        Self {
+            ack_wait: "120s".to_string(),
+            max_ack_pending: 200_000,
+            max_bytes: 5_000_000,
+            max_deliver: 3,
+            backoff: Vec::new(),
+            deliver_policy: DeliverPolicy::All,
+            start_time: None,
+            start_sequence: None,
+            inactive_threshold: "0s".to_string(),
+            max_waiting: 512,
+            replay_policy: consumer::ReplayPolicy::Instant,
+            update_existing: false,
+        }
+    }
+}
+
+impl ConsumerConfig {
+    // The accessors below fall back to defaults on values `validate`
+    // already rejects.
+
+    pub fn ack_wait(&self) -> Duration {
+        humantime::parse_duration(&self.ack_wait).unwrap_or(Duration::from_secs(120))
+    }
+
+    /// The shortest time JetStream waits for an ack before redelivering:
+    /// `ack_wait`, or the smallest `backoff` entry when those are set.
+    pub fn shortest_ack_wait(&self) -> Duration {
+        self.backoff()
+            .into_iter()
+            .fold(self.ack_wait(), Duration::min)
+    }
+
+    pub fn backoff(&self) -> Vec<Duration> {
+        self.backoff
+            .iter()
+            .filter_map(|d| humantime::parse_duration(d).ok())
+            .collect()
+    }
+
+    pub fn inactive_threshold(&self) -> Duration {
+        humantime::parse_duration(&self.inactive_threshold).unwrap_or_default()
+    }
+
+    pub fn deliver_policy(&self) -> consumer::DeliverPolicy {
+        match self.deliver_policy {
+            DeliverPolicy::All => consumer::DeliverPolicy::All,
+            DeliverPolicy::New => consumer::DeliverPolicy::New,
+            DeliverPolicy::ByStartTime => match self.start_time() {
+                Some(start_time) => consumer::DeliverPolicy::ByStartTime { start_time },
+                None => consumer::DeliverPolicy::All,
+            },
+            DeliverPolicy::ByStartSequence => consumer::DeliverPolicy::ByStartSequence {
+                start_sequence: self.start_sequence.unwrap_or(1),
+            },
+        }
+    }
+
+    pub fn start_time(&self) -> Option<time::OffsetDateTime> {
+        let start_time = humantime::parse_rfc3339_weak(self.start_time.as_deref()?).ok()?;
+        Some(start_time.into())
+    }
+}
+
+/// Where a newly created consumer starts reading the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliverPolicy {
+    All,
+    New,
+    ByStartTime,
+    ByStartSequence,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeadLetterConfig {
+    pub subject: String,
+    /// Stream capturing `subject`; created if it does not exist.
+    pub stream: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(remote = "stream::RetentionPolicy", rename_all = "lowercase")]
 pub enum RetentionPolicyDef {
     Limits,
@@ -84,35 +585,458 @@ pub enum RetentionPolicyDef {
     WorkQueue,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(remote = "stream::DiscardPolicy", rename_all = "lowercase")]
 pub enum DiscardPolicyDef {
     Old,
     New,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(remote = "stream::Compression", rename_all = "lowercase")]
+pub enum CompressionDef {
+    None,
+    S2,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(remote = "consumer::ReplayPolicy", rename_all = "lowercase")]
+pub enum ReplayPolicyDef {
+    Instant,
+    Original,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(remote = "stream::StorageType", rename_all = "lowercase")]
 pub enum StorageTypeDef {
     Memory,
     File,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ClickHouseConfig {
     pub host: String,
     pub port: u16,
     pub user: String,
     pub password: String,
     pub database: String,
+    /// Upper bound on concurrent INSERT requests.
     pub max_open_conns: u32,
+    /// Idle keep-alive connections kept in the HTTP pool.
     pub max_idle_conns: u32,
+    /// Log every query and the response headers.
     pub debug: bool,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct BatchConfig {
     pub max_rows: usize,
     pub max_bytes: usize,
     pub flush_interval_ms: u64,
+    /// How many times a batch rejected for bad data or its size may be
+    /// halved while isolating the offending rows.
+    #[serde(default = "default_max_bisect_depth")]
+    pub max_bisect_depth: u32,
+    /// Flushes inserting at once across all tables.
+    #[serde(default = "default_max_concurrent_flushes")]
+    pub max_concurrent_flushes: usize,
+    /// Flushes inserting at once into the same table.
+    #[serde(default = "default_max_concurrent_flushes_per_table")]
+    pub max_concurrent_flushes_per_table: usize,
+    /// Acks (or NAKs, TERMs) in flight at once while settling a flush.
+    #[serde(default = "default_max_concurrent_acks")]
+    pub max_concurrent_acks: usize,
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+fn default_max_bisect_depth() -> u32 {
+    12
+}
+
+fn default_max_concurrent_flushes() -> usize {
+    4
+}
+
+fn default_max_concurrent_flushes_per_table() -> usize {
+    1
+}
+
+fn default_max_concurrent_acks() -> usize {
+    256
+}
+
+/// In-process retries of a failed insert, then the redelivery delay
+/// requested from JetStream when giving up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Fraction of each delay randomized, e.g. 0.2 for ±20%.
+    pub jitter: f64,
+    /// NAK delay after the first delivery; doubled per further delivery.
+    pub nak_base_delay_ms: u64,
+    pub nak_max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 200,
+            max_delay_ms: 5_000,
+            jitter: 0.2,
+            nak_base_delay_ms: 1_000,
+            nak_max_delay_ms: 60_000,
+        }
+    }
+}
+
+/// Maps NATS subjects (wildcards `*` and `>` allowed) to a ClickHouse table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub subject: String,
+    pub table: String,
+    #[serde(default)]
+    pub format: InputFormat,
+    /// `file.proto:Message`; only used, and then required, with `Protobuf`.
+    #[serde(default)]
+    pub format_schema: String,
+    /// ClickHouse settings sent with every insert of this route, e.g.
+    /// `input_format_skip_unknown_fields` or `format_avro_schema_registry_url`.
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+    /// Overrides `clickhouse.database` for this route.
+    #[serde(default)]
+    pub database: Option<String>,
+    /// Longest a row may wait in a batch before it is flushed, overriding
+    /// `batcher.flush_interval_ms` for this route.
+    #[serde(default)]
+    pub max_latency_ms: Option<u64>,
+    #[serde(default)]
+    pub framing: Framing,
+}
+
+/// The ClickHouse input format payloads of a route are in. Framing and
+/// payload validation only apply to `Protobuf`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputFormat {
+    #[default]
+    Protobuf,
+    /// One or more JSON objects per payload, separated by newlines.
+    #[serde(rename = "JSONEachRow")]
+    JsonEachRow,
+    RowBinary,
+    MsgPack,
+    /// Avro datums prefixed with a Confluent schema registry id. Avro object
+    /// container files are not accepted: each carries its own header, so
+    /// files cannot share an insert.
+    AvroConfluent,
+}
+
+/// How producers lay out protobuf payloads. `FORMAT Protobuf` needs every
+/// message prefixed with its varint length.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Framing {
+    /// Payloads are already length-delimited, possibly several messages each.
+    Delimited,
+    /// Each payload is one bare message; the ingester adds the prefix.
+    Single,
+    /// Decided per payload, rejecting ones that are neither.
+    #[default]
+    Auto,
+}
+
+/// Startup check of every route against its ClickHouse table and the
+/// message it references in `format_schema_dir`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchemaCheckConfig {
+    pub enabled: bool,
+    pub format_schema_dir: PathBuf,
+    pub on_mismatch: SchemaMismatchAction,
+}
+
+impl Default for SchemaCheckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            format_schema_dir: PathBuf::from("build/format_schemas"),
+            on_mismatch: SchemaMismatchAction::Fail,
+        }
+    }
+}
+
+/// Checks each payload against its route's message, from the `.proto` files
+/// in `schema_check.format_schema_dir`, before it is batched. Payloads that
+/// fail go to the dead-letter subject instead of failing a whole insert.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PayloadValidationConfig {
+    pub enabled: bool,
+    /// Fields that must be set to a non-default value, in messages that
+    /// declare them.
+    pub required_fields: Vec<String>,
+}
+
+impl Default for PayloadValidationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            required_fields: vec!["event_id".to_string(), "timestamp".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaMismatchAction {
+    /// Refuse to start.
+    Fail,
+    /// Drop the offending routes and keep going.
+    Disable,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MigrationsConfig {
+    pub dir: PathBuf,
+}
+
+impl Default for MigrationsConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("migrations"),
+        }
+    }
+}
+
+/// HTTP listener for `/metrics`, `/healthz` and `/readyz`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonitoringConfig {
+    pub host: String,
+    pub port: u16,
+    /// How often NATS and ClickHouse are probed for readiness.
+    pub probe_interval_ms: u64,
+    /// Readiness fails when rows have been pending for this many times the
+    /// longest flush latency (`batcher.flush_interval_ms` or a route's
+    /// `max_latency_ms`) without a successful flush.
+    pub stuck_flush_intervals: u32,
+    /// Also fail readiness when the NATS server's own `/healthz`, on
+    /// `nats.server_port`, does not answer healthy. Off by default as the
+    /// monitoring port is often not reachable from clients.
+    pub check_nats_server: bool,
+}
+
+impl Default for MonitoringConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 9090,
+            probe_interval_ms: 5_000,
+            stuck_flush_intervals: 10,
+            check_nats_server: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Deref;
+    use std::path::Path;
+
+    /// A file in the temp dir, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "forghoon-config-{}-{}",
+                std::process::id(),
+                name
+            ));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Deref for TempFile {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// A file with what the built-in defaults leave out.
+    fn base_file(name: &str, extra: &str) -> TempFile {
+        let text = format!(
+            "[nats]\nsubjects = [\"events.login\"]\n{}\n\
+             [[routes]]\nsubject = \"events.login\"\ntable = \"login_events\"\n\
+             format_schema = \"dto.proto:LoginEvent\"\n",
+            extra
+        );
+        TempFile::new(&format!("{}.toml", name), &text)
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn load(file: &Path, vars: &[(&str, &str)], overrides: &[&str]) -> Result<AppConfig, String> {
+        let overrides: Vec<String> = overrides.iter().map(|o| o.to_string()).collect();
+        AppConfig::load_from(&[file.to_path_buf()], env(vars), &overrides)
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn env_keys_map_to_dotted_paths() {
+        assert_eq!(env_key("FORGHOON__A__B_C"), "a.b_c");
+        assert_eq!(
+            env_key("FORGHOON__NATS__STREAM_CONFIG__MAX_AGE"),
+            "nats.stream_config.max_age"
+        );
+    }
+
+    #[test]
+    fn later_layers_take_precedence() {
+        let file = base_file("precedence", "[batcher]\nmax_rows = 10");
+        let env_var = [("FORGHOON__BATCHER__MAX_ROWS", "20")];
+        assert_eq!(load(&file, &[], &[]).unwrap().batcher.max_rows, 10);
+        assert_eq!(load(&file, &env_var, &[]).unwrap().batcher.max_rows, 20);
+        let config = load(&file, &env_var, &["batcher.max_rows=30"]).unwrap();
+        assert_eq!(config.batcher.max_rows, 30);
+        // Untouched keys keep the built-in defaults.
+        assert_eq!(config.batcher.flush_interval_ms, 1000);
+    }
+
+    #[test]
+    fn unrelated_env_vars_are_ignored() {
+        let file = base_file("unrelated", "");
+        let config = load(&file, &[("BATCHER__MAX_ROWS", "abc")], &[]).unwrap();
+        assert_eq!(config.batcher.max_rows, 100000);
+    }
+
+    #[test]
+    fn overrides_keep_their_string_form_for_string_keys() {
+        let file = base_file("strings", "");
+        let config = load(
+            &file,
+            &[("FORGHOON__CLICKHOUSE__PASSWORD", "123456")],
+            &["nats.password=true"],
+        )
+        .unwrap();
+        assert_eq!(config.clickhouse.password, "123456");
+        assert_eq!(config.nats.password, "true");
+    }
+
+    #[test]
+    fn secret_files_are_read_and_trimmed() {
+        let secret = TempFile::new("secret", "s3cret\r\n");
+        let file = base_file(
+            "secret",
+            &format!(
+                "[clickhouse]\npassword_file = {}",
+                toml::Value::String(secret.display().to_string())
+            ),
+        );
+        let config = load(&file, &[], &[]).unwrap();
+        assert_eq!(config.clickhouse.password, "s3cret");
+    }
+
+    #[test]
+    fn missing_secret_file_names_its_origin() {
+        let file = base_file("missing-secret", "");
+        let e = load(
+            &file,
+            &[("FORGHOON__NATS__PASSWORD_FILE", "/nonexistent/forghoon")],
+            &[],
+        )
+        .unwrap_err();
+        assert!(e.contains("`nats.password_file`"), "{}", e);
+        assert!(e.contains("env FORGHOON__NATS__PASSWORD_FILE"), "{}", e);
+    }
+
+    #[test]
+    fn errors_name_where_the_value_came_from() {
+        let file = base_file("origins", "[batcher]\nmax_rows = \"many\"");
+        let e = load(&file, &[], &[]).unwrap_err();
+        assert!(e.contains("`batcher.max_rows`"), "{}", e);
+        assert!(e.contains(&file.display().to_string()), "{}", e);
+
+        let file = base_file("origins-env", "");
+        let e = load(&file, &[("FORGHOON__BATCHER__MAX_ROWS", "abc")], &[]).unwrap_err();
+        assert!(e.contains("from env FORGHOON__BATCHER__MAX_ROWS"), "{}", e);
+
+        let e = load(&file, &[], &["batcher.max_rows=true"]).unwrap_err();
+        assert!(e.contains("from --set batcher.max_rows=true"), "{}", e);
+        assert!(e.contains("boolean"), "{}", e);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let file = base_file("unknown-key", "[batcher]\nmax_row = 10");
+        let e = load(&file, &[], &[]).unwrap_err();
+        assert!(e.starts_with("unknown key `batcher.max_row`"), "{}", e);
+        assert!(e.contains(&file.display().to_string()), "{}", e);
+        assert!(e.contains("`max_rows`"), "{}", e);
+
+        let file = base_file("unknown-env", "");
+        let e = load(&file, &[("FORGHOON__NATS__CONSUMER__ACK_WAITS", "1m")], &[]).unwrap_err();
+        assert!(
+            e.starts_with("unknown key `nats.consumer.ack_waits`"),
+            "{}",
+            e
+        );
+        assert!(
+            e.contains("from env FORGHOON__NATS__CONSUMER__ACK_WAITS"),
+            "{}",
+            e
+        );
+
+        let e = load(&file, &[], &["monitoring.prot=1"]).unwrap_err();
+        assert!(e.starts_with("unknown key `monitoring.prot`"), "{}", e);
+        assert!(e.contains("from --set monitoring.prot=1"), "{}", e);
+    }
+
+    #[test]
+    fn deprecated_queue_is_still_accepted() {
+        let file = base_file("queue", "");
+        assert_eq!(load(&file, &[], &[]).unwrap().nats.queue, None);
+        let config = load(&file, &[], &["nats.queue=clickhouse-queue"]).unwrap();
+        assert_eq!(config.nats.queue.as_deref(), Some("clickhouse-queue"));
+    }
+
+    #[test]
+    fn dead_letter_is_unset_unless_configured() {
+        let file = base_file("dead-letter", "");
+        assert!(load(&file, &[], &[]).unwrap().nats.dead_letter.is_none());
+    }
+
+    #[test]
+    fn longest_flush_latency_includes_route_overrides() {
+        let file = base_file("latency", "");
+        let mut config = load(&file, &[], &[]).unwrap();
+        assert_eq!(config.longest_flush_latency(), Duration::from_secs(1));
+        config.routes[0].max_latency_ms = Some(30_000);
+        assert_eq!(config.longest_flush_latency(), Duration::from_secs(30));
+        config.routes[0].max_latency_ms = Some(10);
+        assert_eq!(config.longest_flush_latency(), Duration::from_secs(1));
+    }
 }
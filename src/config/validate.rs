@@ -0,0 +1,567 @@
+use super::{AppConfig, DeliverPolicy, Framing, InputFormat, RetryConfig, RouteConfig};
+use crate::click_house::RESERVED_PARAMS;
+use crate::handler::{is_valid_subject, subject_covers, subject_matches};
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+/// The default NATS `max_payload`; a batch must be able to hold at least
+/// one message of this size.
+pub const NATS_DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+
+/// The shortest `ack_wait` or `backoff` entry accepted. Held messages get
+/// in-progress acks every half of the shortest one, so this keeps that
+/// cadence at 500ms or more.
+const MIN_ACK_WAIT: Duration = Duration::from_secs(1);
+
+/// Every problem found in a configuration, one per line.
+#[derive(Debug)]
+pub struct ValidationErrors(pub Vec<String>);
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problem(s):", self.0.len())?;
+        for e in &self.0 {
+            write!(f, "\n  - {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// Whether `name` is usable as a JetStream stream or consumer name.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '.' | '*' | '>' | '/' | '\\'))
+}
+
+impl AppConfig {
+    /// Checks values and cross-field consistency, collecting every problem
+    /// rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, msg: String| {
+            if !ok {
+                errors.push(msg);
+            }
+        };
+
+        check(
+            EnvFilter::try_new(&self.tracing.level).is_ok(),
+            format!(
+                "tracing.level: `{}` is not a valid filter",
+                self.tracing.level
+            ),
+        );
+
+        let nats = &self.nats;
+        check(!nats.host.is_empty(), "nats.host: must not be empty".into());
+        check(
+            nats.client_port != 0,
+            "nats.client_port: must not be 0".into(),
+        );
+        check(
+            is_valid_name(&nats.consumer_name),
+            format!(
+                "nats.consumer_name: `{}` is not a valid consumer name",
+                nats.consumer_name
+            ),
+        );
+        check(
+            nats.workers != Some(0),
+            "nats.workers: must be greater than 0".into(),
+        );
+        check(
+            !nats.subjects.is_empty(),
+            "nats.subjects: at least one subject is required".into(),
+        );
+        for subject in &nats.subjects {
+            check(
+                is_valid_subject(subject),
+                format!("nats.subjects: `{}` is not a valid subject", subject),
+            );
+            check(
+                self.routes
+                    .iter()
+                    .any(|r| subject_covers(&r.subject, subject)),
+                format!(
+                    "nats.subjects: `{}` is not covered by a single [[routes]] entry; messages no route matches would be terminated",
+                    subject
+                ),
+            );
+        }
+
+        let stream = &nats.stream_config;
+        check(
+            is_valid_name(&stream.name),
+            format!(
+                "nats.stream_config.name: `{}` is not a valid stream name",
+                stream.name
+            ),
+        );
+        check(
+            humantime::parse_duration(&stream.max_age).is_ok(),
+            format!(
+                "nats.stream_config.max_age: `{}` is not a duration (e.g. \"24h\", \"30m\")",
+                stream.max_age
+            ),
+        );
+        check(
+            (1..=5).contains(&stream.replicas),
+            format!(
+                "nats.stream_config.replicas: {} must be between 1 and 5",
+                stream.replicas
+            ),
+        );
+        check(
+            stream.max_msgs == -1 || stream.max_msgs > 0,
+            "nats.stream_config.max_msgs: must be greater than 0, or -1 for no limit".into(),
+        );
+        check(
+            stream.max_bytes == -1 || stream.max_bytes > 0,
+            "nats.stream_config.max_bytes: must be greater than 0, or -1 for no limit".into(),
+        );
+        match humantime::parse_duration(&stream.duplicate_window) {
+            Ok(window) => check(
+                stream.max_age().is_zero() || window <= stream.max_age(),
+                format!(
+                    "nats.stream_config.duplicate_window: `{}` must not exceed max_age",
+                    stream.duplicate_window
+                ),
+            ),
+            Err(_) => check(
+                false,
+                format!(
+                    "nats.stream_config.duplicate_window: `{}` is not a duration (e.g. \"2m\")",
+                    stream.duplicate_window
+                ),
+            ),
+        }
+        check(
+            (1..=i32::MAX as u32).contains(&stream.max_consumers),
+            format!(
+                "nats.stream_config.max_consumers: {} must be between 1 and {}",
+                stream.max_consumers,
+                i32::MAX
+            ),
+        );
+
+        validate_consumer(self, &mut check);
+
+        if let Some(dl) = &nats.dead_letter {
+            check(
+                is_valid_subject(&dl.subject) && !dl.subject.contains(['*', '>']),
+                format!(
+                    "nats.dead_letter.subject: `{}` must be a literal subject",
+                    dl.subject
+                ),
+            );
+            check(
+                !nats
+                    .subjects
+                    .iter()
+                    .any(|s| subject_matches(s, &dl.subject)),
+                format!(
+                    "nats.dead_letter.subject: `{}` is consumed by nats.subjects; dead letters would be re-ingested",
+                    dl.subject
+                ),
+            );
+            check(
+                is_valid_name(&dl.stream) && dl.stream != stream.name,
+                format!(
+                    "nats.dead_letter.stream: `{}` must be a valid name distinct from nats.stream_config.name",
+                    dl.stream
+                ),
+            );
+        }
+
+        let ch = &self.clickhouse;
+        check(
+            !ch.host.is_empty(),
+            "clickhouse.host: must not be empty".into(),
+        );
+        check(ch.port != 0, "clickhouse.port: must not be 0".into());
+        check(
+            !ch.database.is_empty(),
+            "clickhouse.database: must not be empty".into(),
+        );
+        check(
+            ch.max_open_conns > 0,
+            "clickhouse.max_open_conns: must be greater than 0".into(),
+        );
+
+        let batcher = &self.batcher;
+        check(
+            batcher.max_rows > 0,
+            "batcher.max_rows: must be greater than 0".into(),
+        );
+        check(
+            batcher.max_bytes >= NATS_DEFAULT_MAX_PAYLOAD,
+            format!(
+                "batcher.max_bytes: {} is smaller than a single NATS message may be ({} bytes)",
+                batcher.max_bytes, NATS_DEFAULT_MAX_PAYLOAD
+            ),
+        );
+        check(
+            batcher.flush_interval_ms > 0,
+            "batcher.flush_interval_ms: must be greater than 0".into(),
+        );
+        check(
+            batcher.max_concurrent_flushes > 0,
+            "batcher.max_concurrent_flushes: must be greater than 0".into(),
+        );
+        check(
+            (1..=batcher.max_concurrent_flushes.max(1))
+                .contains(&batcher.max_concurrent_flushes_per_table),
+            "batcher.max_concurrent_flushes_per_table: must be between 1 and max_concurrent_flushes"
+                .into(),
+        );
+        check(
+            batcher.max_concurrent_acks > 0,
+            "batcher.max_concurrent_acks: must be greater than 0".into(),
+        );
+        validate_retry(&batcher.retry, &mut check);
+
+        let mut seen = HashSet::new();
+        for (i, route) in self.routes.iter().enumerate() {
+            validate_route(i, route, &mut check);
+            check(
+                seen.insert(route.subject.as_str()),
+                format!(
+                    "routes[{}].subject: `{}` is already routed by an earlier entry",
+                    i, route.subject
+                ),
+            );
+        }
+
+        if self.schema_check.enabled || self.payload_validation.enabled {
+            check(
+                self.schema_check.format_schema_dir.is_dir(),
+                format!(
+                    "schema_check.format_schema_dir: {} is not a directory",
+                    self.schema_check.format_schema_dir.display()
+                ),
+            );
+        }
+
+        check(
+            self.monitoring.probe_interval_ms > 0,
+            "monitoring.probe_interval_ms: must be greater than 0".into(),
+        );
+        check(
+            self.monitoring.stuck_flush_intervals > 0,
+            "monitoring.stuck_flush_intervals: must be greater than 0".into(),
+        );
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationErrors(errors))
+        }
+    }
+}
+
+fn validate_consumer(config: &AppConfig, check: &mut impl FnMut(bool, String)) {
+    let consumer = &config.nats.consumer;
+    let is_duration = |d: &str| humantime::parse_duration(d).is_ok();
+    let is_ack_wait = |d: &str| humantime::parse_duration(d).is_ok_and(|d| d >= MIN_ACK_WAIT);
+
+    check(
+        is_ack_wait(&consumer.ack_wait),
+        format!(
+            "nats.consumer.ack_wait: `{}` is not a duration of at least {} (e.g. \"120s\")",
+            consumer.ack_wait,
+            humantime::format_duration(MIN_ACK_WAIT)
+        ),
+    );
+    check(
+        consumer.max_ack_pending == -1 || consumer.max_ack_pending > 0,
+        "nats.consumer.max_ack_pending: must be greater than 0, or -1 for no limit".into(),
+    );
+    check(
+        consumer.max_bytes >= 0,
+        "nats.consumer.max_bytes: must not be negative".into(),
+    );
+    check(
+        consumer.max_deliver == -1 || consumer.max_deliver > 0,
+        "nats.consumer.max_deliver: must be greater than 0, or -1 for no limit".into(),
+    );
+    for (i, delay) in consumer.backoff.iter().enumerate() {
+        check(
+            is_ack_wait(delay),
+            format!(
+                "nats.consumer.backoff[{}]: `{}` is not a duration of at least {}",
+                i,
+                delay,
+                humantime::format_duration(MIN_ACK_WAIT)
+            ),
+        );
+    }
+    check(
+        consumer.backoff.is_empty()
+            || consumer.max_deliver == -1
+            || consumer.max_deliver > consumer.backoff.len() as i64,
+        format!(
+            "nats.consumer.backoff: {} entries require max_deliver greater than that",
+            consumer.backoff.len()
+        ),
+    );
+    check(
+        is_duration(&consumer.inactive_threshold),
+        format!(
+            "nats.consumer.inactive_threshold: `{}` is not a duration",
+            consumer.inactive_threshold
+        ),
+    );
+    check(
+        consumer.max_waiting > 0,
+        "nats.consumer.max_waiting: must be greater than 0".into(),
+    );
+    match consumer.deliver_policy {
+        DeliverPolicy::ByStartTime => check(
+            consumer.start_time().is_some(),
+            "nats.consumer.start_time: an RFC 3339 UTC timestamp is required by deliver_policy \"by_start_time\"".into(),
+        ),
+        DeliverPolicy::ByStartSequence => check(
+            consumer.start_sequence.is_some_and(|s| s > 0),
+            "nats.consumer.start_sequence: a sequence of at least 1 is required by deliver_policy \"by_start_sequence\"".into(),
+        ),
+        DeliverPolicy::All | DeliverPolicy::New => {}
+    }
+}
+
+fn validate_retry(retry: &RetryConfig, check: &mut impl FnMut(bool, String)) {
+    check(
+        retry.max_attempts > 0,
+        "batcher.retry.max_attempts: must be at least 1".into(),
+    );
+    check(
+        retry.base_delay_ms <= retry.max_delay_ms,
+        "batcher.retry.base_delay_ms: must not exceed max_delay_ms".into(),
+    );
+    check(
+        (0.0..=1.0).contains(&retry.jitter),
+        format!(
+            "batcher.retry.jitter: {} must be between 0 and 1",
+            retry.jitter
+        ),
+    );
+    check(
+        retry.nak_base_delay_ms <= retry.nak_max_delay_ms,
+        "batcher.retry.nak_base_delay_ms: must not exceed nak_max_delay_ms".into(),
+    );
+}
+
+fn validate_route(i: usize, route: &RouteConfig, check: &mut impl FnMut(bool, String)) {
+    check(
+        is_valid_subject(&route.subject),
+        format!(
+            "routes[{}].subject: `{}` is not a valid subject pattern",
+            i, route.subject
+        ),
+    );
+    check(
+        !route.table.is_empty(),
+        format!("routes[{}].table: must not be empty", i),
+    );
+    if route.format == InputFormat::Protobuf {
+        check(
+            route
+                .format_schema
+                .split_once(':')
+                .is_some_and(|(file, message)| !file.is_empty() && !message.is_empty()),
+            format!(
+                "routes[{}].format_schema: `{}` must look like file.proto:Message",
+                i, route.format_schema
+            ),
+        );
+    } else {
+        check(
+            route.format_schema.is_empty(),
+            format!(
+                "routes[{}].format_schema: only used with format = \"Protobuf\"",
+                i
+            ),
+        );
+        check(
+            route.framing == Framing::Auto,
+            format!(
+                "routes[{}].framing: only used with format = \"Protobuf\"",
+                i
+            ),
+        );
+    }
+    if route.format == InputFormat::AvroConfluent {
+        check(
+            route
+                .settings
+                .contains_key("format_avro_schema_registry_url"),
+            format!(
+                "routes[{}].settings: AvroConfluent needs format_avro_schema_registry_url",
+                i
+            ),
+        );
+    }
+    for name in route.settings.keys() {
+        check(
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            format!(
+                "routes[{}].settings: `{}` is not a ClickHouse setting name",
+                i, name
+            ),
+        );
+        check(
+            !RESERVED_PARAMS.contains(&name.as_str()),
+            format!(
+                "routes[{}].settings: `{}` is set by the ingester itself",
+                i, name
+            ),
+        );
+    }
+    check(
+        route.database.as_ref().is_none_or(|db| !db.is_empty()),
+        format!("routes[{}].database: must not be empty when set", i),
+    );
+    check(
+        route.max_latency_ms != Some(0),
+        format!("routes[{}].max_latency_ms: must be greater than 0", i),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DEFAULTS, DeadLetterConfig};
+
+    fn route(subject: &str) -> RouteConfig {
+        RouteConfig {
+            subject: subject.to_string(),
+            table: "login_events".to_string(),
+            format: InputFormat::Protobuf,
+            format_schema: "dto.proto:LoginEvent".to_string(),
+            settings: Default::default(),
+            database: None,
+            max_latency_ms: None,
+            framing: Framing::Auto,
+        }
+    }
+
+    fn valid() -> AppConfig {
+        let mut config: AppConfig = toml::from_str(DEFAULTS).unwrap();
+        config.nats.subjects = vec!["events.login".to_string()];
+        config.routes = vec![route("events.login")];
+        config.schema_check.format_schema_dir = std::env::temp_dir();
+        config
+    }
+
+    fn errors(config: &AppConfig) -> Vec<String> {
+        config.validate().err().map(|e| e.0).unwrap_or_default()
+    }
+
+    #[test]
+    fn the_built_in_defaults_with_a_route_are_valid() {
+        assert_eq!(errors(&valid()), Vec::<String>::new());
+    }
+
+    /// A case name, a change that breaks a valid configuration, and the
+    /// start of the expected error.
+    type Case = (&'static str, fn(&mut AppConfig), &'static str);
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let cases: &[Case] = &[
+            (
+                "subject wider than its route",
+                |c| c.nats.subjects = vec!["events.>".to_string()],
+                "nats.subjects: `events.>` is not covered",
+            ),
+            (
+                "subject without a route",
+                |c| c.nats.subjects.push("other.login".to_string()),
+                "nats.subjects: `other.login` is not covered",
+            ),
+            (
+                "dead letters consumed again",
+                |c| {
+                    c.nats.subjects = vec!["events.>".to_string()];
+                    c.routes = vec![route("events.>")];
+                    c.nats.dead_letter = Some(DeadLetterConfig {
+                        subject: "events.dead".to_string(),
+                        stream: "DeadLetters".to_string(),
+                    });
+                },
+                "nats.dead_letter.subject: `events.dead` is consumed",
+            ),
+            (
+                "more backoff entries than deliveries",
+                |c| {
+                    c.nats.consumer.max_deliver = 2;
+                    c.nats.consumer.backoff = vec!["10s".to_string(), "1m".to_string()];
+                },
+                "nats.consumer.backoff: 2 entries require max_deliver",
+            ),
+            (
+                "duplicate window longer than max_age",
+                |c| {
+                    c.nats.stream_config.max_age = "1m".to_string();
+                    c.nats.stream_config.duplicate_window = "2m".to_string();
+                },
+                "nats.stream_config.duplicate_window: `2m` must not exceed max_age",
+            ),
+            (
+                "zero ack_wait",
+                |c| c.nats.consumer.ack_wait = "0s".to_string(),
+                "nats.consumer.ack_wait: `0s` is not a duration of at least 1s",
+            ),
+            (
+                "backoff entry too short",
+                |c| c.nats.consumer.backoff = vec!["100ms".to_string()],
+                "nats.consumer.backoff[0]: `100ms` is not a duration of at least 1s",
+            ),
+            (
+                "route subject routed twice",
+                |c| c.routes.push(route("events.login")),
+                "routes[1].subject: `events.login` is already routed",
+            ),
+        ];
+        for (name, change, expected) in cases {
+            let mut config = valid();
+            change(&mut config);
+            let errors = errors(&config);
+            assert!(
+                errors.iter().any(|e| e.starts_with(expected)),
+                "{}: expected `{}` in {:?}",
+                name,
+                expected,
+                errors
+            );
+        }
+    }
+
+    #[test]
+    fn subjects_covered_by_a_wildcard_route_are_valid() {
+        let mut config = valid();
+        config.nats.subjects = vec!["events.*".to_string(), "events.angulak.>".to_string()];
+        config.routes = vec![route("events.login"), route("events.>")];
+        assert_eq!(errors(&config), Vec::<String>::new());
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let mut config = valid();
+        config.nats.host = String::new();
+        config.batcher.max_rows = 0;
+        config.monitoring.probe_interval_ms = 0;
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.0.len(), 3, "{:?}", errors.0);
+        assert!(
+            errors
+                .to_string()
+                .starts_with("3 problem(s):\n  - nats.host")
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use reqwest::StatusCode;
+use std::fmt;
+
+/// ClickHouse exception codes the ingester reacts to. See
+/// `src/Common/ErrorCodes.cpp` in the ClickHouse sources.
+pub mod codes {
+    pub const UNEXPECTED_END_OF_FILE: u32 = 3;
+    pub const CANNOT_PARSE_TEXT: u32 = 6;
+    pub const NO_SUCH_COLUMN_IN_TABLE: u32 = 16;
+    pub const CANNOT_PARSE_QUOTED_STRING: u32 = 26;
+    pub const CANNOT_PARSE_INPUT_ASSERTION_FAILED: u32 = 27;
+    pub const ATTEMPT_TO_READ_AFTER_EOF: u32 = 32;
+    pub const CANNOT_READ_ALL_DATA: u32 = 33;
+    pub const CANNOT_PARSE_DATE: u32 = 38;
+    pub const CANNOT_PARSE_DATETIME: u32 = 41;
+    pub const UNKNOWN_IDENTIFIER: u32 = 47;
+    pub const TYPE_MISMATCH: u32 = 53;
+    pub const UNKNOWN_TABLE: u32 = 60;
+    pub const SYNTAX_ERROR: u32 = 62;
+    pub const CANNOT_CONVERT_TYPE: u32 = 70;
+    pub const CANNOT_PARSE_NUMBER: u32 = 72;
+    pub const UNKNOWN_FORMAT: u32 = 73;
+    pub const UNKNOWN_DATABASE: u32 = 81;
+    pub const INCORRECT_DATA: u32 = 117;
+    pub const TIMEOUT_EXCEEDED: u32 = 159;
+    pub const TOO_MANY_SIMULTANEOUS_QUERIES: u32 = 202;
+    pub const NO_FREE_CONNECTION: u32 = 203;
+    pub const SOCKET_TIMEOUT: u32 = 209;
+    pub const NETWORK_ERROR: u32 = 210;
+    pub const ABORTED: u32 = 236;
+    pub const MEMORY_LIMIT_EXCEEDED: u32 = 241;
+    pub const TABLE_IS_READ_ONLY: u32 = 242;
+    pub const TOO_MANY_PARTS: u32 = 252;
+    pub const TOO_FEW_LIVE_REPLICAS: u32 = 285;
+    pub const UNKNOWN_STATUS_OF_INSERT: u32 = 319;
+    pub const VALUE_IS_OUT_OF_RANGE_OF_DATA_TYPE: u32 = 321;
+    pub const QUERY_WAS_CANCELLED: u32 = 394;
+    pub const CANNOT_PARSE_PROTOBUF_SCHEMA: u32 = 434;
+    pub const NO_COLUMN_SERIALIZED_TO_REQUIRED_PROTOBUF_FIELD: u32 = 435;
+    pub const PROTOBUF_BAD_CAST: u32 = 436;
+    pub const CANNOT_PARSE_DOMAIN_VALUE_FROM_STRING: u32 = 441;
+    pub const NO_COLUMNS_SERIALIZED_TO_PROTOBUF_FIELDS: u32 = 443;
+    pub const UNKNOWN_PROTOBUF_FORMAT: u32 = 444;
+    pub const DEADLOCK_AVOIDED: u32 = 473;
+    pub const KEEPER_EXCEPTION: u32 = 999;
+}
+
+/// What the batcher should do with the rows of a failed insert.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The same request is expected to succeed later.
+    Retryable,
+    /// Retrying the same rows will never succeed.
+    Permanent,
+    /// Not enough information; treated like `Retryable` so nothing is lost.
+    Unknown,
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorClass::Retryable => "retryable",
+            ErrorClass::Permanent => "permanent",
+            ErrorClass::Unknown => "unknown",
+        })
+    }
+}
+
+/// Failure of a request to the ClickHouse HTTP interface.
+#[derive(Debug)]
+pub enum ClickHouseError {
+    /// No response was received (connect failure, timeout, reset).
+    Transport(reqwest::Error),
+    /// ClickHouse answered with a non-success status.
+    Server {
+        status: StatusCode,
+        /// From `X-ClickHouse-Exception-Code`, or `Code: N.` in the body.
+        code: Option<u32>,
+        message: String,
+    },
+}
+
+impl ClickHouseError {
+    pub async fn from_response(resp: reqwest::Response) -> Self {
+        let status = resp.status();
+        let header_code = resp
+            .headers()
+            .get("X-ClickHouse-Exception-Code")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok());
+        let message = resp.text().await.unwrap_or_default();
+        let code = header_code.or_else(|| parse_exception_code(&message));
+        ClickHouseError::Server {
+            status,
+            code,
+            message: message.trim().to_string(),
+        }
+    }
+
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClickHouseError::Transport(e) => e.status(),
+            ClickHouseError::Server { status, .. } => Some(*status),
+        }
+    }
+
+    pub fn code(&self) -> Option<u32> {
+        match self {
+            ClickHouseError::Transport(_) => None,
+            ClickHouseError::Server { code, .. } => *code,
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            ClickHouseError::Transport(e) => {
+                if e.is_connect() || e.is_timeout() || e.is_request() {
+                    ErrorClass::Retryable
+                } else {
+                    ErrorClass::Unknown
+                }
+            }
+            ClickHouseError::Server {
+                code: Some(code), ..
+            } => classify_code(*code),
+            ClickHouseError::Server { status, .. } => classify_status(*status),
+        }
+    }
+
+    /// Whether splitting the batch can help: ClickHouse rejected the rows
+    /// themselves, so halving isolates the offending ones, or the body was
+    /// too large for a proxy in front of it, so smaller inserts may pass.
+    pub fn is_splittable(&self) -> bool {
+        use codes::*;
+        match self.code() {
+            Some(code) => matches!(
+                code,
+                CANNOT_PARSE_TEXT
+                    | CANNOT_PARSE_QUOTED_STRING
+                    | CANNOT_PARSE_INPUT_ASSERTION_FAILED
+                    | ATTEMPT_TO_READ_AFTER_EOF
+                    | CANNOT_READ_ALL_DATA
+                    | CANNOT_PARSE_DATE
+                    | CANNOT_PARSE_DATETIME
+                    | CANNOT_CONVERT_TYPE
+                    | CANNOT_PARSE_NUMBER
+                    | INCORRECT_DATA
+                    | VALUE_IS_OUT_OF_RANGE_OF_DATA_TYPE
+                    | PROTOBUF_BAD_CAST
+                    | CANNOT_PARSE_DOMAIN_VALUE_FROM_STRING
+                    | UNKNOWN_PROTOBUF_FORMAT
+            ),
+            None => matches!(
+                self.status(),
+                Some(StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE)
+            ),
+        }
+    }
+}
+
+fn classify_code(code: u32) -> ErrorClass {
+    use codes::*;
+    match code {
+        UNEXPECTED_END_OF_FILE
+        | TIMEOUT_EXCEEDED
+        | TOO_MANY_SIMULTANEOUS_QUERIES
+        | NO_FREE_CONNECTION
+        | SOCKET_TIMEOUT
+        | NETWORK_ERROR
+        | ABORTED
+        | MEMORY_LIMIT_EXCEEDED
+        | TABLE_IS_READ_ONLY
+        | TOO_MANY_PARTS
+        | TOO_FEW_LIVE_REPLICAS
+        | UNKNOWN_STATUS_OF_INSERT
+        | QUERY_WAS_CANCELLED
+        | DEADLOCK_AVOIDED
+        | KEEPER_EXCEPTION => ErrorClass::Retryable,
+        CANNOT_PARSE_TEXT
+        | NO_SUCH_COLUMN_IN_TABLE
+        | CANNOT_PARSE_QUOTED_STRING
+        | CANNOT_PARSE_INPUT_ASSERTION_FAILED
+        | ATTEMPT_TO_READ_AFTER_EOF
+        | CANNOT_READ_ALL_DATA
+        | CANNOT_PARSE_DATE
+        | CANNOT_PARSE_DATETIME
+        | UNKNOWN_IDENTIFIER
+        | TYPE_MISMATCH
+        | UNKNOWN_TABLE
+        | SYNTAX_ERROR
+        | CANNOT_CONVERT_TYPE
+        | CANNOT_PARSE_NUMBER
+        | UNKNOWN_FORMAT
+        | UNKNOWN_DATABASE
+        | INCORRECT_DATA
+        | VALUE_IS_OUT_OF_RANGE_OF_DATA_TYPE
+        | CANNOT_PARSE_PROTOBUF_SCHEMA
+        | NO_COLUMN_SERIALIZED_TO_REQUIRED_PROTOBUF_FIELD
+        | PROTOBUF_BAD_CAST
+        | CANNOT_PARSE_DOMAIN_VALUE_FROM_STRING
+        | NO_COLUMNS_SERIALIZED_TO_PROTOBUF_FIELDS
+        | UNKNOWN_PROTOBUF_FORMAT => ErrorClass::Permanent,
+        _ => ErrorClass::Unknown,
+    }
+}
+
+/// Fallback for responses without an exception code (proxies, load balancers).
+fn classify_status(status: StatusCode) -> ErrorClass {
+    match status {
+        StatusCode::REQUEST_TIMEOUT
+        | StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => ErrorClass::Retryable,
+        StatusCode::BAD_REQUEST
+        | StatusCode::NOT_FOUND
+        | StatusCode::PAYLOAD_TOO_LARGE
+        | StatusCode::UNPROCESSABLE_ENTITY => ErrorClass::Permanent,
+        _ => ErrorClass::Unknown,
+    }
+}
+
+/// Extracts `N` from a `Code: N. DB::Exception: ...` response body.
+fn parse_exception_code(body: &str) -> Option<u32> {
+    let rest = &body[body.find("Code: ")? + "Code: ".len()..];
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+impl fmt::Display for ClickHouseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClickHouseError::Transport(e) => write!(f, "CH request failed: {}", e),
+            ClickHouseError::Server {
+                status, message, ..
+            } => write!(f, "CH request failed {}: {}", status, message),
+        }
+    }
+}
+
+impl std::error::Error for ClickHouseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClickHouseError::Transport(e) => Some(e),
+            ClickHouseError::Server { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClickHouseError {
+    fn from(e: reqwest::Error) -> Self {
+        ClickHouseError::Transport(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn server_error(status: u16, header: Option<&str>, body: &str) -> ClickHouseError {
+        let mut builder = axum::http::Response::builder().status(status);
+        if let Some(code) = header {
+            builder = builder.header("X-ClickHouse-Exception-Code", code);
+        }
+        let resp = builder.body(body.to_string()).unwrap();
+        ClickHouseError::from_response(reqwest::Response::from(resp)).await
+    }
+
+    #[test]
+    fn codes_map_to_classes() {
+        assert_eq!(
+            classify_code(codes::CANNOT_PARSE_INPUT_ASSERTION_FAILED),
+            ErrorClass::Permanent
+        );
+        assert_eq!(classify_code(27), ErrorClass::Permanent);
+        assert_eq!(classify_code(241), ErrorClass::Retryable);
+        assert_eq!(classify_code(252), ErrorClass::Retryable);
+        assert_eq!(classify_code(319), ErrorClass::Retryable);
+        assert_eq!(classify_code(60), ErrorClass::Permanent);
+        assert_eq!(classify_code(1_000_000), ErrorClass::Unknown);
+    }
+
+    #[test]
+    fn statuses_map_to_classes() {
+        assert_eq!(
+            classify_status(StatusCode::SERVICE_UNAVAILABLE),
+            ErrorClass::Retryable
+        );
+        assert_eq!(
+            classify_status(StatusCode::PAYLOAD_TOO_LARGE),
+            ErrorClass::Permanent
+        );
+        assert_eq!(
+            classify_status(StatusCode::INTERNAL_SERVER_ERROR),
+            ErrorClass::Unknown
+        );
+    }
+
+    #[test]
+    fn exception_codes_are_parsed_from_the_body() {
+        assert_eq!(
+            parse_exception_code("Code: 27. DB::Exception: Cannot parse input"),
+            Some(27)
+        );
+        assert_eq!(
+            parse_exception_code(
+                "Code: 241. DB::Exception: Memory limit (total) exceeded: 252 bytes"
+            ),
+            Some(241)
+        );
+        // Digits elsewhere in the message are not a code.
+        assert_eq!(
+            parse_exception_code("Too many parts (252). Merges are slower"),
+            None
+        );
+        assert_eq!(parse_exception_code("Code: x"), None);
+        assert_eq!(parse_exception_code(""), None);
+    }
+
+    #[tokio::test]
+    async fn header_code_takes_precedence_over_body() {
+        let e = server_error(
+            500,
+            Some("252"),
+            "Code: 27. DB::Exception: Cannot parse input",
+        )
+        .await;
+        assert_eq!(e.code(), Some(252));
+        assert_eq!(e.class(), ErrorClass::Retryable);
+
+        let e = server_error(500, None, "Code: 27. DB::Exception: Cannot parse input").await;
+        assert_eq!(e.code(), Some(27));
+        assert_eq!(e.class(), ErrorClass::Permanent);
+        assert!(e.is_splittable());
+
+        // An unparsable header falls back to the body.
+        let e = server_error(500, Some("n/a"), "Code: 241. DB::Exception: Memory limit").await;
+        assert_eq!(e.code(), Some(241));
+        assert_eq!(e.class(), ErrorClass::Retryable);
+    }
+
+    #[tokio::test]
+    async fn responses_without_a_code_use_the_status() {
+        let e = server_error(503, None, "upstream unavailable").await;
+        assert_eq!(e.code(), None);
+        assert_eq!(e.class(), ErrorClass::Retryable);
+
+        let e = server_error(413, None, "<html>Request Entity Too Large</html>").await;
+        assert_eq!(e.class(), ErrorClass::Permanent);
+        assert!(e.is_splittable());
+
+        let e = server_error(404, None, "not found").await;
+        assert_eq!(e.class(), ErrorClass::Permanent);
+        assert!(!e.is_splittable());
+    }
+}
@@ -1,194 +1,686 @@
-use crate::click_house::ClickHouseClient;
+use crate::click_house::{ClickHouseClient, InsertFormat, InsertTarget};
+use crate::config;
+use crate::error::{ClickHouseError, ErrorClass};
+use crate::health::Health;
+use crate::metrics::METRICS;
+use crate::nats::{self, DeadLetter};
+use crate::protobuf::{Framed, MessageSchema};
+use crate::retry::RetryPolicy;
 use async_nats::jetstream::{AckKind, Message};
-use std::collections::HashMap;
-use tokio::sync::mpsc;
+use bytes::Bytes;
+use futures::{StreamExt, stream};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::{Semaphore, mpsc};
+use tokio::task::JoinSet;
 use tokio::{select, time};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
-#[derive(Clone)]
+#[derive(Debug)]
 pub struct Route {
-    pub table: &'static str,
-    pub format_schema: &'static str,
-}
-
-pub fn route_for_subject(subject: &str) -> Option<Route> {
-    match subject {
-        "events.login" => Some(Route {
-            table: "login_events",
-            format_schema: "dto.proto:LoginEvent",
-        }),
-        "events.sabte_ahval" => Some(Route {
-            table: "sabte_ahval_events",
-            format_schema: "dto.proto:SabteAhvalEvent",
-        }),
-        "events.angulak.like" => Some(Route {
-            table: "angulak_like_events",
-            format_schema: "dto.proto:AngulakLikeEvent",
-        }),
-        "events.angulak.watch" => Some(Route {
-            table: "angulak_watch_events",
-            format_schema: "dto.proto:AngulakWatchEvent",
-        }),
-        "events.session" => Some(Route {
-            table: "session_events",
-            format_schema: "dto.proto:SessionEvent",
-        }),
-        "events.angulak.comment" => Some(Route {
-            table: "angulak_comment_events",
-            format_schema: "dto.proto:AngulakCommentEvent",
-        }),
-        "events.shahrefarang.item" => Some(Route {
-            table: "shahrefarang_item_events",
-            format_schema: "dto.proto:ShahreFarangItemEvent",
-        }),
-        "events.shahrefarang.play_info" => Some(Route {
-            table: "shahrefarang_play_info_events",
-            format_schema: "dto.proto:ShahreFarangPlayInfoEvent",
-        }),
-        "events.angulak.bookmark" => Some(Route {
-            table: "angulak_bookmark_events",
-            format_schema: "dto.proto:AngulakBookmarkEvent",
-        }),
-        _ => None,
+    pub database: Option<String>,
+    pub table: String,
+    pub format: config::InputFormat,
+    pub format_schema: String,
+    pub settings: BTreeMap<String, String>,
+    pub max_latency: Option<time::Duration>,
+    pub framing: config::Framing,
+    /// Checks payloads before they are batched, when payload validation is on.
+    pub schema: Option<Arc<MessageSchema>>,
+}
+
+/// Resolves a message subject to its route. Entries are tried in
+/// configuration order and the first matching pattern wins.
+pub struct Router {
+    routes: Vec<(String, Arc<Route>)>,
+}
+
+impl Router {
+    pub fn new(
+        routes: Vec<config::RouteConfig>,
+        schemas: &HashMap<String, Arc<MessageSchema>>,
+    ) -> Self {
+        Self {
+            routes: routes
+                .into_iter()
+                .map(|r| {
+                    (
+                        r.subject,
+                        Arc::new(Route {
+                            schema: schemas.get(&r.format_schema).cloned(),
+                            database: r.database,
+                            table: r.table,
+                            format: r.format,
+                            format_schema: r.format_schema,
+                            settings: r.settings,
+                            max_latency: r.max_latency_ms.map(time::Duration::from_millis),
+                            framing: r.framing,
+                        }),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn route_for_subject(&self, subject: &str) -> Option<Arc<Route>> {
+        self.routes
+            .iter()
+            .find(|(pattern, _)| subject_matches(pattern, subject))
+            .map(|(_, route)| route.clone())
+    }
+}
+
+/// Whether `pattern` is a well-formed subject: non-empty tokens, `*` only
+/// as a whole token and `>` only as the last one.
+pub fn is_valid_subject(pattern: &str) -> bool {
+    let tokens: Vec<&str> = pattern.split('.').collect();
+    tokens.iter().enumerate().all(|(i, t)| {
+        !t.is_empty()
+            && !t.contains(char::is_whitespace)
+            && (!t.contains('*') || *t == "*")
+            && (!t.contains('>') || (*t == ">" && i == tokens.len() - 1))
+    })
+}
+
+/// Whether every subject `subject` can match is also matched by `pattern`.
+pub fn subject_covers(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+    for p in pattern.split('.') {
+        match (p, subject_tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(s)) if s != ">" => {}
+            (p, Some(s)) if p == s => {}
+            _ => return false,
+        }
+    }
+    subject_tokens.next().is_none()
+}
+
+/// NATS subject matching: `*` matches exactly one token, a trailing `>`
+/// matches one or more tokens.
+pub fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+    for p in pattern.split('.') {
+        match (p, subject_tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (p, Some(s)) if p == s => {}
+            _ => return false,
+        }
+    }
+    subject_tokens.next().is_none()
+}
+
+/// Where a message sits in its stream, read once from its JetStream reply
+/// subject.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamPosition {
+    pub sequence: u64,
+    /// When the stream stored the message, in nanoseconds since the epoch.
+    pub published: i128,
+    pub delivered: i64,
+}
+
+impl StreamPosition {
+    pub fn of(msg: &Message) -> Result<Self, async_nats::Error> {
+        let info = msg.info()?;
+        Ok(Self {
+            sequence: info.stream_sequence,
+            published: info.published.unix_timestamp_nanos(),
+            delivered: info.delivered,
+        })
     }
 }
 
 struct BatchItem {
-    payload: Vec<u8>,
+    /// Shares the message's buffer; never copied before the insert.
+    row: Framed,
     msg: Message,
+    position: StreamPosition,
+}
+
+/// Batches are per subject. Redelivered messages are kept apart from new
+/// ones, so a batch whose acks were lost, which JetStream redelivers all
+/// together, is not mixed with newer messages and usually comes back as the
+/// same set.
+///
+/// Boundaries still depend on timing, not on sequences alone: nothing records
+/// where the first attempt's batch ended, so a redelivered set that is split
+/// differently gets different tokens and its rows are inserted again. Those
+/// copies are removed per row instead, by the `event_id` sorting key of the
+/// ReplacingMergeTree tables (migration 013).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BatchKey {
+    subject: String,
+    redelivered: bool,
+}
+
+/// Identifies one insert by its subject and its messages' stream positions.
+/// An insert of exactly the same messages carries the same token, so
+/// ClickHouse drops the repeat instead of duplicating rows. Publish times are
+/// hashed along with the sequences: a recreated stream starts again at
+/// sequence 1 and must not repeat tokens still in the dedup window.
+/// `rows` are sorted by sequence.
+fn dedup_token(subject: &str, rows: &[StreamPosition]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(subject.as_bytes());
+    for position in rows {
+        hasher.update(position.sequence.to_be_bytes());
+        hasher.update(position.published.to_be_bytes());
+    }
+    format!(
+        "{}:{}-{}:{:x}",
+        subject,
+        rows.first().map_or(0, |r| r.sequence),
+        rows.last().map_or(0, |r| r.sequence),
+        hasher.finalize()
+    )
+}
+
+/// Rows `items` insert; a delimited payload can carry several.
+fn row_count(items: &[BatchItem]) -> usize {
+    items.iter().map(|item| item.row.count).sum()
 }
 
 struct SubjectBatch {
-    route: Route,
+    route: Arc<Route>,
     rows: Vec<BatchItem>,
     bytes: usize,
+    /// When the first row was added.
+    created_at: time::Instant,
+    /// When the rows last had their `ack_wait` restarted, at the latest.
+    last_progress: time::Instant,
 }
 
-pub struct Batcher {
+/// What a flush task needs, shared by every in-flight flush.
+struct Flusher {
     ch: ClickHouseClient,
+    dead_letter: Option<DeadLetter>,
+    health: Arc<Health>,
+    max_bisect_depth: u32,
+    max_concurrent_acks: usize,
+    /// Held messages get an in-progress ack this often; half the shortest
+    /// time after which JetStream would redeliver them.
+    progress_interval: time::Duration,
+    retry: RetryPolicy,
+}
+
+impl Flusher {
+    /// Inserts `rows`, retrying non-permanent failures with backoff. Every
+    /// attempt carries a deduplication token derived from the rows, so one
+    /// that succeeded unnoticed is not inserted twice.
+    async fn insert_with_retry(
+        &self,
+        subject: &str,
+        route: &Route,
+        rows: &[BatchItem],
+    ) -> Result<(), ClickHouseError> {
+        let (format, body): (_, Vec<Vec<Bytes>>) = match rows {
+            // A lone bare message goes without the prefix the ingester added.
+            [item] if item.row.is_single() => (
+                InsertFormat::ProtobufSingle,
+                vec![vec![item.row.payload.clone()]],
+            ),
+            _ => (
+                InsertFormat::from(route.format),
+                rows.iter()
+                    .map(|item| vec![item.row.prefix.clone(), item.row.payload.clone()])
+                    .collect(),
+            ),
+        };
+        let target = InsertTarget {
+            database: route.database.as_deref(),
+            table: &route.table,
+            format,
+            format_schema: &route.format_schema,
+            settings: &route.settings,
+        };
+        let positions: Vec<StreamPosition> = rows.iter().map(|item| item.position).collect();
+        let token = dedup_token(subject, &positions);
+        let mut attempt = 1;
+        loop {
+            let result = self.ch.insert_batch(&target, &token, &body).await;
+            if let Err(e) = &result {
+                METRICS
+                    .flush_failures
+                    .with_label_values(&[route.table.as_str(), &e.class().to_string()])
+                    .inc();
+            }
+            match result {
+                Err(e)
+                    if e.class() != ErrorClass::Permanent
+                        && attempt < self.retry.max_attempts() =>
+                {
+                    let delay = self.retry.delay(attempt);
+                    warn!(
+                        "Insert for subject {} failed (attempt {}/{}), retrying in {:?}: {}",
+                        subject,
+                        attempt,
+                        self.retry.max_attempts(),
+                        delay,
+                        e
+                    );
+                    time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends `ack_kind(msg)` for each of `rows`, up to `max_concurrent_acks`
+    /// at a time. Failures are counted and logged once for the whole range;
+    /// a message whose ack is lost is redelivered and inserted again.
+    async fn ack_rows(
+        &self,
+        subject: &str,
+        rows: &[BatchItem],
+        label: &str,
+        ack_kind: impl Fn(&BatchItem) -> AckKind,
+    ) {
+        // Collected first: building them inside the stream trips up the
+        // `Send` check on the spawned flush task.
+        let acks: Vec<_> = rows
+            .iter()
+            .map(|item| nats::ack_with(&item.msg, ack_kind(item), label))
+            .collect();
+        let mut acks = stream::iter(acks).buffer_unordered(self.max_concurrent_acks);
+        let mut failed = 0;
+        let mut last_error = None;
+        while let Some(result) = acks.next().await {
+            if let Err(e) = result {
+                failed += 1;
+                last_error = Some(e);
+            }
+        }
+        if let Some(e) = last_error {
+            warn!(
+                "{} failed for {} of {} messages of subject {}, last error: {}",
+                label,
+                failed,
+                rows.len(),
+                subject,
+                e
+            );
+        }
+    }
+
+    /// Tells JetStream that `rows`, held since `held_since`, are still being
+    /// worked on, restarting their `ack_wait` so they are not redelivered
+    /// while we hold the originals.
+    async fn send_progress(&self, subject: &str, rows: &[BatchItem], held_since: time::Instant) {
+        info!(
+            "Sending in-progress acks for {} messages of subject {} held for {:?}.",
+            rows.len(),
+            subject,
+            held_since.elapsed()
+        );
+        self.ack_rows(subject, rows, "progress", |_| AckKind::Progress)
+            .await;
+    }
+
+    /// Runs `work`, sending in-progress acks for the rows of `batch` every
+    /// `progress_interval` until it completes.
+    async fn keep_alive<F: Future>(
+        &self,
+        subject: &str,
+        batch: &SubjectBatch,
+        work: F,
+    ) -> F::Output {
+        tokio::pin!(work);
+        let mut next = batch.last_progress + self.progress_interval;
+        loop {
+            select! {
+                biased;
+                output = &mut work => return output,
+                _ = time::sleep_until(next) => {
+                    self.send_progress(subject, &batch.rows, batch.created_at).await;
+                    next = time::Instant::now() + self.progress_interval;
+                }
+            }
+        }
+    }
+
+    /// Inserts `batch`. When ClickHouse rejects the data itself, the batch is
+    /// split in halves (up to `max_bisect_depth` levels) so the good rows
+    /// still land and only the bad ones are dead-lettered.
+    async fn flush(&self, subject: &str, batch: &SubjectBatch) {
+        let route = batch.route.clone();
+        let started = time::Instant::now();
+        METRICS
+            .batch_rows
+            .with_label_values(&[route.table.as_str()])
+            .observe(row_count(&batch.rows) as f64);
+        let mut inserted = 0;
+        let mut salvaged = 0;
+        let mut rejected = 0;
+        let mut nacked = 0;
+        // (start, end, depth) ranges of `batch.rows`, left half on top.
+        let mut pending = vec![(0, batch.rows.len(), 0)];
+
+        while let Some((start, end, depth)) = pending.pop() {
+            let rows = &batch.rows[start..end];
+            let e = match self.insert_with_retry(subject, &route, rows).await {
+                Ok(_) => {
+                    self.health.flush_succeeded();
+                    inserted += row_count(rows);
+                    if depth > 0 {
+                        salvaged += row_count(rows);
+                    }
+                    METRICS
+                        .rows_flushed
+                        .with_label_values(&[route.table.as_str()])
+                        .inc_by(row_count(rows) as u64);
+                    METRICS
+                        .bytes_flushed
+                        .with_label_values(&[route.table.as_str()])
+                        .inc_by(rows.iter().map(|item| item.row.len() as u64).sum());
+                    self.ack_rows(subject, rows, "ack", |_| AckKind::Ack).await;
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            let class = e.class();
+            if depth == 0 {
+                error!("Flush failed for subject {} ({}): {}", subject, class, e);
+            } else {
+                debug!(
+                    "Flush of rows {}..{} failed for subject {} at depth {} ({}): {}",
+                    start, end, subject, depth, class, e
+                );
+            }
+
+            if class != ErrorClass::Permanent {
+                nacked += row_count(rows);
+                self.ack_rows(subject, rows, "nak", |item| {
+                    AckKind::Nak(Some(self.retry.nak_delay(item.position.delivered)))
+                })
+                .await;
+                continue;
+            }
+
+            if e.is_splittable() && rows.len() > 1 && depth < self.max_bisect_depth {
+                let mid = start + rows.len() / 2;
+                pending.push((mid, end, depth + 1));
+                pending.push((start, mid, depth + 1));
+                continue;
+            }
+
+            rejected += row_count(rows);
+            let reason = e.to_string();
+            stream::iter(rows)
+                .for_each_concurrent(self.max_concurrent_acks, |item| {
+                    nats::term_message(self.dead_letter.as_ref(), &item.msg, &reason, Some(&e))
+                })
+                .await;
+        }
+
+        METRICS
+            .flush_duration
+            .with_label_values(&[route.table.as_str()])
+            .observe(started.elapsed().as_secs_f64());
+
+        if salvaged > 0 || rejected > 0 || nacked > 0 {
+            info!(
+                "Flushed {} of {} rows to {} ({} salvaged by bisection, {} rejected, {} NAK'd).",
+                inserted,
+                row_count(&batch.rows),
+                route.table,
+                salvaged,
+                rejected,
+                nacked
+            );
+        } else {
+            info!("Flushed {} rows to {}.", inserted, route.table);
+        }
+    }
+}
+
+pub struct Batcher {
+    flusher: Arc<Flusher>,
+    health: Arc<Health>,
     max_rows: usize,
     max_bytes: usize,
     flush_interval: time::Duration,
+    max_concurrent_flushes: usize,
+    max_concurrent_flushes_per_table: usize,
+    flush_slots: Arc<Semaphore>,
+    table_slots: HashMap<(Option<String>, String), Arc<Semaphore>>,
+    in_flight: JoinSet<()>,
 
-    batches: HashMap<String, SubjectBatch>,
+    batches: HashMap<BatchKey, SubjectBatch>,
 }
 
 impl Batcher {
     pub fn new(
         ch: ClickHouseClient,
-        max_rows: usize,
-        max_bytes: usize,
-        flush_interval_ms: u64,
+        dead_letter: Option<DeadLetter>,
+        health: Arc<Health>,
+        batch_config: config::BatchConfig,
+        ack_wait: time::Duration,
     ) -> Self {
         Self {
-            ch,
-            max_rows,
-            max_bytes,
-            flush_interval: time::Duration::from_millis(flush_interval_ms),
+            flusher: Arc::new(Flusher {
+                ch,
+                dead_letter,
+                health: health.clone(),
+                max_bisect_depth: batch_config.max_bisect_depth,
+                max_concurrent_acks: batch_config.max_concurrent_acks,
+                progress_interval: ack_wait / 2,
+                retry: RetryPolicy::new(batch_config.retry),
+            }),
+            health,
+            max_rows: batch_config.max_rows,
+            max_bytes: batch_config.max_bytes,
+            flush_interval: time::Duration::from_millis(batch_config.flush_interval_ms),
+            max_concurrent_flushes: batch_config.max_concurrent_flushes,
+            max_concurrent_flushes_per_table: batch_config.max_concurrent_flushes_per_table,
+            flush_slots: Arc::new(Semaphore::new(batch_config.max_concurrent_flushes)),
+            table_slots: Default::default(),
+            in_flight: JoinSet::new(),
             batches: Default::default(),
         }
     }
 
-    fn add(&mut self, subject: String, route: Route, payload: Vec<u8>, msg: Message) {
+    /// Adds a message to its batch and returns that batch's key.
+    fn add(
+        &mut self,
+        subject: String,
+        route: Arc<Route>,
+        row: Framed,
+        msg: Message,
+        position: StreamPosition,
+    ) -> BatchKey {
+        let key = BatchKey {
+            subject,
+            redelivered: position.delivered > 1,
+        };
         let entry = self
             .batches
-            .entry(subject.clone())
+            .entry(key.clone())
             .or_insert_with(|| SubjectBatch {
                 route,
                 rows: Vec::with_capacity(self.max_rows),
                 bytes: 0,
+                created_at: time::Instant::now(),
+                last_progress: time::Instant::now(),
             });
-        entry.bytes += payload.len();
-        entry.rows.push(BatchItem { payload, msg });
+        entry.bytes += row.len();
+        entry.rows.push(BatchItem { row, msg, position });
+        self.update_pending();
+        key
     }
 
-    async fn flush_subject(&mut self, subject: &str) {
-        if let Some(batch) = self.batches.remove(subject) {
-            if batch.rows.is_empty() {
-                return;
-            }
-            let rows_bytes: Vec<Vec<u8>> = batch.rows.iter().map(|b| b.payload.clone()).collect();
-            let route = batch.route.clone();
-
-            match self
-                .ch
-                .insert_protobuf_batch(route.table, route.format_schema, &rows_bytes)
-                .await
-            {
-                Ok(_) => {
-                    info!("Flushed {} rows to {}.", batch.rows.len(), route.table);
-                    for item in batch.rows {
-                        let _ = item.msg.ack().await;
-                    }
-                }
-                Err(e) => {
-                    error!("Flush failed for subject {}: {}", subject, e);
-                    let permanent = is_permanent_ch_error(&e.to_string());
-                    for item in batch.rows {
-                        let _ = if permanent {
-                            item.msg.ack_with(AckKind::Term).await
-                        } else {
-                            item.msg.ack_with(AckKind::Nak(None)).await
-                        };
-                    }
-                }
+    fn update_pending(&self) {
+        self.health
+            .set_pending_rows(self.batches.values().map(|b| b.rows.len()).sum());
+    }
+
+    /// Hands the batch for `key` to a flush task, so the loop keeps filling
+    /// the next batch while this one is inserted. Only waits when twice
+    /// `max_concurrent_flushes` batches are already queued.
+    async fn flush_batch(&mut self, key: &BatchKey) {
+        let Some(mut batch) = self.batches.remove(key) else {
+            return;
+        };
+        if batch.rows.is_empty() {
+            return;
+        }
+        // Redeliveries may arrive in another order; sorting makes the
+        // bisection ranges, and so their tokens, the same every time.
+        batch
+            .rows
+            .sort_unstable_by_key(|item| item.position.sequence);
+        self.update_pending();
+        while self.in_flight.len() >= 2 * self.max_concurrent_flushes {
+            self.join_next().await;
+        }
+
+        let table_slot = self
+            .table_slots
+            .entry((batch.route.database.clone(), batch.route.table.clone()))
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_concurrent_flushes_per_table)))
+            .clone();
+        let flush_slot = self.flush_slots.clone();
+        let flusher = self.flusher.clone();
+        let subject = key.subject.clone();
+        self.in_flight.spawn(async move {
+            let flush = async {
+                // Table slot first, so a batch queued behind another insert
+                // into its table does not hold a global slot meanwhile.
+                let _table = table_slot
+                    .acquire_owned()
+                    .await
+                    .expect("flush semaphore closed");
+                let _slot = flush_slot
+                    .acquire_owned()
+                    .await
+                    .expect("flush semaphore closed");
+                METRICS.flushes_in_flight.inc();
+                flusher.flush(&subject, &batch).await;
+                METRICS.flushes_in_flight.dec();
+            };
+            flusher.keep_alive(&subject, &batch, flush).await;
+        });
+    }
+
+    /// Waits for one flush task to finish.
+    async fn join_next(&mut self) {
+        if let Some(Err(e)) = self.in_flight.join_next().await {
+            error!("Flush task failed: {}", e);
+        }
+    }
+
+    /// When `batch` must be flushed even if it is not full.
+    fn due_at(&self, batch: &SubjectBatch) -> time::Instant {
+        batch.created_at + batch.route.max_latency.unwrap_or(self.flush_interval)
+    }
+
+    /// The earliest `due_at` of all open batches.
+    fn next_due(&self) -> Option<time::Instant> {
+        self.batches.values().map(|b| self.due_at(b)).min()
+    }
+
+    /// When the open batch held longest needs in-progress acks.
+    fn next_progress(&self) -> Option<time::Instant> {
+        self.batches
+            .values()
+            .map(|b| b.last_progress + self.flusher.progress_interval)
+            .min()
+    }
+
+    /// Sends in-progress acks for open batches that have been held for
+    /// `progress_interval`, e.g. on a route with a long `max_latency_ms`.
+    async fn send_progress_due(&mut self) {
+        let now = time::Instant::now();
+        let flusher = self.flusher.clone();
+        for (key, batch) in self.batches.iter_mut() {
+            if batch.last_progress + flusher.progress_interval <= now {
+                flusher
+                    .send_progress(&key.subject, &batch.rows, batch.created_at)
+                    .await;
+                batch.last_progress = time::Instant::now();
             }
         }
     }
 
+    /// Flushes every batch that is full or has been open for its route's
+    /// latency.
     async fn flush_due(&mut self) {
-        let subjects: Vec<String> = self
+        let now = time::Instant::now();
+        let keys: Vec<BatchKey> = self
             .batches
             .iter()
-            .filter_map(|(s, b)| {
-                if b.rows.len() >= self.max_rows || b.bytes >= self.max_bytes {
-                    Some(s.clone())
+            .filter_map(|(k, b)| {
+                if b.rows.len() >= self.max_rows
+                    || b.bytes >= self.max_bytes
+                    || self.due_at(b) <= now
+                {
+                    Some(k.clone())
                 } else {
                     None
                 }
             })
             .collect();
 
-        for s in subjects {
-            self.flush_subject(&s).await;
+        for k in keys {
+            self.flush_batch(&k).await;
         }
     }
 
+    /// Flushes every batch and waits for all flushes to finish.
     async fn flush_all(&mut self) {
-        let subjects: Vec<String> = self.batches.keys().cloned().collect();
-        for s in subjects {
-            self.flush_subject(&s).await;
+        let keys: Vec<BatchKey> = self.batches.keys().cloned().collect();
+        for k in keys {
+            self.flush_batch(&k).await;
+        }
+        while !self.in_flight.is_empty() {
+            self.join_next().await;
         }
     }
 
     pub async fn run(
         mut self,
-        mut rx: mpsc::Receiver<(String, Route, Vec<u8>, Message)>,
+        mut rx: mpsc::Receiver<(String, Arc<Route>, Framed, Message, StreamPosition)>,
         shutdown: CancellationToken,
     ) {
-        let mut ticker = time::interval(self.flush_interval);
+        let _alive = self.health.batcher_guard();
         loop {
+            let next_due = self
+                .next_due()
+                .into_iter()
+                .chain(self.next_progress())
+                .min();
             select! {
                 _ = shutdown.cancelled() => {
                     info!("Batcher shutdown: flushing all.");
                     self.flush_all().await;
                     break;
                 }
-                _ = ticker.tick() => {
+                _ = time::sleep_until(next_due.unwrap_or_else(time::Instant::now)), if next_due.is_some() => {
                     self.flush_due().await;
+                    self.send_progress_due().await;
+                }
+                Some(result) = self.in_flight.join_next(), if !self.in_flight.is_empty() => {
+                    if let Err(e) = result {
+                        error!("Flush task failed: {}", e);
+                    }
                 }
                 maybe_item = rx.recv() => {
+                    METRICS.channel_depth.set(rx.len() as i64);
                     match maybe_item {
-                        Some((subject, route, payload, msg)) => {
-                            self.add(subject.clone(), route.clone(), payload, msg);
+                        Some((subject, route, row, msg, position)) => {
+                            let key = self.add(subject, route, row, msg, position);
                             // Re-fetch and flush if needed
                             let need_flush = {
-                                let b = self.batches.get(&subject).unwrap();
+                                let b = self.batches.get(&key).unwrap();
                                 b.rows.len() >= self.max_rows || b.bytes >= self.max_bytes
                             };
                             if need_flush {
-                                self.flush_subject(&subject).await;
+                                self.flush_batch(&key).await;
                             }
                         }
                         None => {
@@ -203,10 +695,129 @@ impl Batcher {
     }
 }
 
-fn is_permanent_ch_error(s: &str) -> bool {
-    s.contains("400")
-        || s.contains("404")
-        || s.contains("422")
-        || s.contains("Cannot parse")
-        || s.contains("444")
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_subjects_match_exactly() {
+        assert!(subject_matches("events.login", "events.login"));
+        assert!(!subject_matches("events.login", "events.logout"));
+        assert!(!subject_matches("events.login", "events"));
+        assert!(!subject_matches("events.login", "events.login.extra"));
+    }
+
+    #[test]
+    fn star_matches_exactly_one_token() {
+        assert!(subject_matches("events.*", "events.login"));
+        assert!(subject_matches("events.*.like", "events.angulak.like"));
+        assert!(!subject_matches("events.*", "events"));
+        assert!(!subject_matches("events.*", "events.angulak.like"));
+        assert!(!subject_matches("events.*.like", "events.angulak.watch"));
+    }
+
+    #[test]
+    fn trailing_gt_matches_one_or_more_tokens() {
+        assert!(subject_matches("events.>", "events.login"));
+        assert!(subject_matches("events.>", "events.angulak.like"));
+        assert!(subject_matches(">", "events"));
+        assert!(!subject_matches("events.>", "events"));
+        assert!(!subject_matches("events.>", "other.login"));
+    }
+
+    #[test]
+    fn subject_validity() {
+        for valid in [
+            "events",
+            "events.login",
+            "events.*",
+            "events.*.like",
+            "events.>",
+            ">",
+        ] {
+            assert!(is_valid_subject(valid), "{}", valid);
+        }
+        for invalid in [
+            "",
+            "events.",
+            ".events",
+            "events..login",
+            "events.log in",
+            "events.lo*",
+            "events.>.login",
+            "events.a>",
+        ] {
+            assert!(!is_valid_subject(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn covering_patterns() {
+        assert!(subject_covers("events.login", "events.login"));
+        assert!(subject_covers("events.*", "events.login"));
+        assert!(subject_covers("events.*", "events.*"));
+        assert!(subject_covers("events.>", "events.angulak.*"));
+        assert!(subject_covers("events.>", "events.>"));
+        assert!(subject_covers(">", "events.*"));
+        assert!(!subject_covers("events.login", "events.>"));
+        assert!(!subject_covers("events.login", "events.*"));
+        assert!(!subject_covers("events.*", "events.>"));
+        assert!(!subject_covers("events.*.like", "events.angulak.*"));
+        assert!(!subject_covers("events.>", "events"));
+        assert!(!subject_covers("events.login", "events.login.extra"));
+    }
+
+    fn position(sequence: u64, published: i128) -> StreamPosition {
+        StreamPosition {
+            sequence,
+            published,
+            delivered: 1,
+        }
+    }
+
+    #[test]
+    fn dedup_tokens_depend_on_the_exact_messages() {
+        let rows = [position(3, 100), position(7, 200)];
+        let token = dedup_token("events.login", &rows);
+        assert!(token.starts_with("events.login:3-7:"), "{}", token);
+        assert_eq!(token, dedup_token("events.login", &rows));
+        assert_ne!(token, dedup_token("events.logout", &rows));
+        assert_ne!(token, dedup_token("events.login", &rows[..1]));
+        // The same sequences in a recreated stream.
+        assert_ne!(
+            token,
+            dedup_token("events.login", &[position(3, 900), position(7, 1000)])
+        );
+    }
+
+    fn route(subject: &str, table: &str) -> config::RouteConfig {
+        config::RouteConfig {
+            subject: subject.to_string(),
+            table: table.to_string(),
+            format: config::InputFormat::Protobuf,
+            format_schema: "dto.proto:LoginEvent".to_string(),
+            settings: Default::default(),
+            database: None,
+            max_latency_ms: None,
+            framing: config::Framing::Auto,
+        }
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let router = Router::new(
+            vec![
+                route("events.login", "logins"),
+                route("events.*", "events"),
+                route("events.>", "nested"),
+            ],
+            &HashMap::new(),
+        );
+        let table = |subject| router.route_for_subject(subject).map(|r| r.table.clone());
+        assert_eq!(table("events.login").as_deref(), Some("logins"));
+        assert_eq!(table("events.session").as_deref(), Some("events"));
+        assert_eq!(table("events.angulak.like").as_deref(), Some("nested"));
+        assert_eq!(table("other.login"), None);
+        assert_eq!(table("events"), None);
+    }
 }
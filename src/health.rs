@@ -0,0 +1,153 @@
+use crate::click_house::ClickHouseClient;
+use crate::nats::Nats;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::time;
+use tracing::warn;
+
+/// Liveness and readiness state shared between the pipeline components and
+/// the `/healthz` and `/readyz` endpoints.
+pub struct Health {
+    started: Instant,
+    nats_connected: AtomicBool,
+    nats_server_ok: AtomicBool,
+    consumer_exists: AtomicBool,
+    clickhouse_ok: AtomicBool,
+    batcher_alive: AtomicBool,
+    pending_rows: AtomicUsize,
+    /// Milliseconds since `started` of the last successful flush, or of the
+    /// moment rows started pending if nothing was flushed since.
+    last_flush_ms: AtomicU64,
+    stuck_after: Duration,
+}
+
+impl Health {
+    /// `stuck_after` is how long rows may stay pending without a successful
+    /// flush before the batcher is considered stuck.
+    pub fn new(stuck_after: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            started: Instant::now(),
+            nats_connected: AtomicBool::new(false),
+            // Only probed when `monitoring.check_nats_server` is set.
+            nats_server_ok: AtomicBool::new(true),
+            consumer_exists: AtomicBool::new(false),
+            clickhouse_ok: AtomicBool::new(false),
+            batcher_alive: AtomicBool::new(false),
+            pending_rows: AtomicUsize::new(0),
+            last_flush_ms: AtomicU64::new(0),
+            stuck_after,
+        })
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    pub fn set_nats_connected(&self, ok: bool) {
+        self.nats_connected.store(ok, Ordering::Relaxed);
+    }
+
+    pub fn set_nats_server_ok(&self, ok: bool) {
+        self.nats_server_ok.store(ok, Ordering::Relaxed);
+    }
+
+    pub fn set_consumer_exists(&self, ok: bool) {
+        self.consumer_exists.store(ok, Ordering::Relaxed);
+    }
+
+    pub fn set_clickhouse_ok(&self, ok: bool) {
+        self.clickhouse_ok.store(ok, Ordering::Relaxed);
+    }
+
+    pub fn flush_succeeded(&self) {
+        self.last_flush_ms.store(self.now_ms(), Ordering::Relaxed);
+    }
+
+    pub fn set_pending_rows(&self, rows: usize) {
+        let previous = self.pending_rows.swap(rows, Ordering::Relaxed);
+        if previous == 0 && rows > 0 {
+            // Start the stuck timer when rows begin to pile up, not at the
+            // last flush which may have been long ago on a quiet pipeline.
+            self.flush_succeeded();
+        }
+    }
+
+    /// Marks the batcher alive until the returned guard is dropped, which
+    /// also happens if its task panics.
+    pub fn batcher_guard(self: &Arc<Self>) -> BatcherGuard {
+        self.batcher_alive.store(true, Ordering::Relaxed);
+        BatcherGuard(self.clone())
+    }
+
+    /// Failed liveness checks; empty when live.
+    pub fn liveness(&self) -> Vec<&'static str> {
+        let mut failed = Vec::new();
+        if !self.batcher_alive.load(Ordering::Relaxed) {
+            failed.push("batcher task is not running");
+        }
+        failed
+    }
+
+    /// Failed readiness checks; empty when ready.
+    pub fn readiness(&self) -> Vec<&'static str> {
+        let mut failed = self.liveness();
+        if !self.nats_connected.load(Ordering::Relaxed) {
+            failed.push("NATS is not connected");
+        }
+        if !self.nats_server_ok.load(Ordering::Relaxed) {
+            failed.push("NATS server health check failed");
+        }
+        if !self.consumer_exists.load(Ordering::Relaxed) {
+            failed.push("JetStream consumer does not exist");
+        }
+        if !self.clickhouse_ok.load(Ordering::Relaxed) {
+            failed.push("last ClickHouse ping failed");
+        }
+        // A flush finishing between the two reads may store a later time.
+        let idle_ms = self
+            .now_ms()
+            .saturating_sub(self.last_flush_ms.load(Ordering::Relaxed));
+        if self.pending_rows.load(Ordering::Relaxed) > 0
+            && Duration::from_millis(idle_ms) > self.stuck_after
+        {
+            failed.push("batcher has pending rows but no recent successful flush");
+        }
+        failed
+    }
+}
+
+pub struct BatcherGuard(Arc<Health>);
+
+impl Drop for BatcherGuard {
+    fn drop(&mut self) {
+        self.0.batcher_alive.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Refreshes the NATS and ClickHouse checks every `interval`, and the NATS
+/// server's own health check if `check_nats_server`. Never returns.
+pub async fn probe(
+    health: &Health,
+    nats: &Nats,
+    ch: &ClickHouseClient,
+    interval: Duration,
+    check_nats_server: bool,
+) {
+    let mut ticker = time::interval(interval);
+    loop {
+        ticker.tick().await;
+        health.set_nats_connected(nats.is_connected());
+        if check_nats_server {
+            health.set_nats_server_ok(nats.server_healthy().await);
+        }
+        health.set_consumer_exists(nats.consumer_exists().await);
+        match ch.ping().await {
+            Ok(()) => health.set_clickhouse_ok(true),
+            Err(e) => {
+                warn!("ClickHouse ping failed: {}", e);
+                health.set_clickhouse_ok(false);
+            }
+        }
+    }
+}
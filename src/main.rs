@@ -1,46 +1,184 @@
-use crate::handler::Route;
+use crate::cli::Command;
+use crate::handler::{Route, StreamPosition};
+use crate::metrics::METRICS;
+use crate::protobuf::Framed;
 use async_nats::jetstream::Message;
 use async_nats::jetstream::message::AckKind;
+use clap::Parser;
 use futures::StreamExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio_util::sync::CancellationToken;
 
 use tokio::signal;
 use tokio::sync::mpsc;
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
+mod cli;
 mod click_house;
 mod config;
 mod error;
 mod handler;
+mod health;
+mod metrics;
+mod migrate;
 mod nats;
+mod protobuf;
+mod retry;
+mod schema;
+mod server;
 
 #[tokio::main]
 async fn main() {
-    let app_configs = config::AppConfig::load_from_file("config/default.toml").unwrap();
+    let cli = cli::Cli::parse();
 
-    init_tracing(app_configs.tracing.clone());
+    match cli.command.as_ref().unwrap_or(&Command::Run) {
+        Command::Version => println!("forghoon {}", env!("CARGO_PKG_VERSION")),
+        Command::CheckConfig => {
+            load_config(&cli);
+            println!("configuration ok");
+        }
+        Command::PrintConfig => match load_config(&cli).to_redacted_toml() {
+            Ok(text) => print!("{}", text),
+            Err(e) => {
+                eprintln!("print config: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Command::Migrate { action } => {
+            let app_configs = load_config(&cli);
+            init_tracing(app_configs.tracing.clone());
+            let clickhouse_client =
+                click_house::ClickHouseClient::new(app_configs.clickhouse.clone());
+            if let Err(e) =
+                migrate::run(&clickhouse_client, &app_configs.migrations.dir, action).await
+            {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Run => {
+            let app_configs = load_config(&cli);
+            init_tracing(app_configs.tracing.clone());
+            run(app_configs).await;
+        }
+    }
+}
+
+fn load_config(cli: &cli::Cli) -> config::AppConfig {
+    let mut paths = cli.config.clone();
+    if paths.is_empty() && Path::new(config::DEFAULT_CONFIG_PATH).exists() {
+        paths.push(config::DEFAULT_CONFIG_PATH.into());
+    }
+    match config::AppConfig::load(&paths, &cli.overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+async fn run(app_configs: config::AppConfig) {
     let shutdown = CancellationToken::new();
-    let nats_client = nats::Nats::new(app_configs.nats.clone()).await.unwrap();
+    let health = health::Health::new(
+        app_configs.longest_flush_latency() * app_configs.monitoring.stuck_flush_intervals,
+    );
+    let listener = match server::bind(&app_configs.monitoring).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(
+                "cannot bind monitoring endpoint {}:{}: {}",
+                app_configs.monitoring.host, app_configs.monitoring.port, e
+            );
+            std::process::exit(1);
+        }
+    };
+    let monitoring_task = {
+        let shutdown = shutdown.clone();
+        let health = health.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server::serve(listener, health, shutdown).await {
+                error!("Monitoring endpoint failed: {}", e);
+            }
+        })
+    };
+    let nats_client = match nats::Nats::new(app_configs.nats.clone()).await {
+        Ok(client) => client,
+        Err(e) => {
+            error!("NATS setup failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if app_configs.batcher.max_bytes < nats_client.max_payload() {
+        warn!(
+            "batcher.max_bytes ({}) is below the server's max_payload ({}); large messages will be flushed alone",
+            app_configs.batcher.max_bytes,
+            nats_client.max_payload()
+        );
+    }
     let clickhouse_client = click_house::ClickHouseClient::new(app_configs.clickhouse.clone());
-    clickhouse_client.ping().await.unwrap();
+    if let Err(e) = clickhouse_client.ping().await {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    let protos = schema::ProtoFiles::load(
+        &app_configs.routes,
+        &app_configs.schema_check.format_schema_dir,
+    );
+    let routes = match schema::validate_routes(
+        &clickhouse_client,
+        app_configs.routes.clone(),
+        &app_configs.schema_check,
+        &app_configs.clickhouse.database,
+        &protos,
+    )
+    .await
+    {
+        Ok(routes) => routes,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let schemas = match schema::message_schemas(&routes, &app_configs.payload_validation, &protos) {
+        Ok(schemas) => schemas,
+        Err(e) => {
+            error!("payload validation: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let router = handler::Router::new(routes, &schemas);
     let batcher = handler::Batcher::new(
-        clickhouse_client,
-        app_configs.batcher.max_rows,
-        app_configs.batcher.max_bytes,
-        app_configs.batcher.flush_interval_ms,
+        clickhouse_client.clone(),
+        nats_client.dead_letter(),
+        health.clone(),
+        app_configs.batcher.clone(),
+        app_configs.nats.consumer.shortest_ack_wait(),
     );
 
-    let (tx, rx) = mpsc::channel::<(String, Route, Vec<u8>, Message)>(app_configs.batcher.max_rows);
+    let (tx, rx) = mpsc::channel::<(String, Arc<Route>, Framed, Message, StreamPosition)>(
+        app_configs.batcher.max_rows,
+    );
     let batcher_task = {
         let shutdown = shutdown.clone();
         tokio::spawn(batcher.run(rx, shutdown))
     };
-    let messages = nats_client.consume().await.unwrap();
+    let messages = match nats_client.consume().await {
+        Ok(messages) => messages,
+        Err(e) => {
+            error!("cannot start consuming: {}", e);
+            std::process::exit(1);
+        }
+    };
+    health.set_consumer_exists(true);
 
-    let concurrency = std::env::var("WORKERS")
-        .ok()
-        .and_then(|v| v.parse().ok())
+    let concurrency = app_configs
+        .nats
+        .workers
+        .or_else(|| std::env::var("WORKERS").ok().and_then(|v| v.parse().ok()))
         .unwrap_or_else(|| {
             std::thread::available_parallelism()
                 .map(|n| (n.get() * 4).clamp(8, 256))
@@ -50,6 +188,9 @@ async fn main() {
     info!("Start consuming messages..., limit {}", concurrency);
 
     let tx_for_processing = tx.clone();
+    let router = &router;
+    let dead_letter = nats_client.dead_letter();
+    let dead_letter = dead_letter.as_ref();
 
     let processing = messages.for_each_concurrent(concurrency, |message| {
         let tx = tx_for_processing.clone();
@@ -65,26 +206,64 @@ async fn main() {
             // info!("Received a message: {:?}", message);
 
             let subject = message.subject.clone();
-            let Some(route) = handler::route_for_subject(&subject) else {
+            METRICS
+                .messages_received
+                .with_label_values(&[subject.as_str()])
+                .inc();
+            // Without its stream sequence a message cannot be given a
+            // deduplication token.
+            let position = match StreamPosition::of(&message) {
+                Ok(position) => position,
+                Err(e) => {
+                    warn!(
+                        "Rejecting message on {} without JetStream metadata: {}",
+                        subject, e
+                    );
+                    nats::term_message(dead_letter, &message, "no JetStream metadata", None).await;
+                    return;
+                }
+            };
+            let Some(route) = router.route_for_subject(&subject) else {
                 warn!("No route found for subject: {}", subject);
-                let _ = message.ack_with(AckKind::Term).await;
+                nats::term_message(dead_letter, &message, "no route for subject", None).await;
                 return;
             };
 
+            let checked = match (route.format, &route.schema) {
+                (config::InputFormat::Protobuf, Some(schema)) => {
+                    protobuf::frame_validated(message.payload.clone(), route.framing, schema)
+                        .map_err(|e| e.to_string())
+                }
+                (config::InputFormat::Protobuf, None) => {
+                    protobuf::frame(message.payload.clone(), route.framing)
+                        .map_err(|e| e.to_string())
+                }
+                _ => Ok(Framed::raw(message.payload.clone())),
+            };
+            let row = match checked {
+                Ok(row) => row,
+                Err(e) => {
+                    warn!("Rejecting message on {}: {}", subject, e);
+                    METRICS
+                        .payloads_rejected
+                        .with_label_values(&[subject.as_str()])
+                        .inc();
+                    nats::term_message(dead_letter, &message, &e, None).await;
+                    return;
+                }
+            };
+
             match tx
-                .send((
-                    subject.into_string(),
-                    route,
-                    message.payload.to_vec(),
-                    message,
-                ))
+                .send((subject.into_string(), route, row, message, position))
                 .await
             {
                 Ok(()) => {}
                 Err(err) => {
                     warn!("Batcher channel closed; NAK message for retry.");
-                    let (_, _, _, message) = err.0;
-                    let _ = message.ack_with(AckKind::Nak(None)).await;
+                    let (_, _, _, message, _) = err.0;
+                    if let Err(e) = nats::ack_with(&message, AckKind::Nak(None), "nak").await {
+                        warn!("NAK for {} failed: {}", message.subject, e);
+                    }
                 }
             }
         }
@@ -98,11 +277,19 @@ async fn main() {
         _ = processing => {
             info!("Message processing completed.");
         }
+        _ = health::probe(
+            &health,
+            &nats_client,
+            &clickhouse_client,
+            Duration::from_millis(app_configs.monitoring.probe_interval_ms),
+            app_configs.monitoring.check_nats_server,
+        ) => {}
     }
 
     drop(tx);
     let _ = nats_client.close().await;
     let _ = batcher_task.await;
+    let _ = monitoring_task.await;
     info!("NATS client closed, exiting.");
 }
 
@@ -0,0 +1,169 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+    exponential_buckets,
+};
+use std::sync::LazyLock;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Messages pulled from JetStream, by subject.
+    pub messages_received: IntCounterVec,
+    /// Payloads rejected before batching because they could not be framed or
+    /// failed validation, by subject.
+    pub payloads_rejected: IntCounterVec,
+    /// Rows and payload bytes inserted into ClickHouse, by table.
+    pub rows_flushed: IntCounterVec,
+    pub bytes_flushed: IntCounterVec,
+    /// Wall time of a whole flush including retries and bisection, by table.
+    pub flush_duration: HistogramVec,
+    /// Failed inserts, by table and error class.
+    pub flush_failures: IntCounterVec,
+    /// Rows in a batch when it is flushed, by table.
+    pub batch_rows: HistogramVec,
+    /// Acknowledgements sent to JetStream, by kind (ack, nak, term, progress).
+    pub acks: IntCounterVec,
+    /// Acknowledgements that could not be sent, by kind. A failed ack means
+    /// the message is redelivered and inserted again.
+    pub ack_failures: IntCounterVec,
+    /// Messages waiting in the channel between the consumers and the batcher.
+    pub channel_depth: IntGauge,
+    /// Flushes currently inserting into ClickHouse.
+    pub flushes_in_flight: IntGauge,
+    /// ClickHouse HTTP responses, by status code (`transport` when none).
+    pub clickhouse_responses: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("forghoon".to_string()), None).expect("metrics registry");
+
+        let messages_received = IntCounterVec::new(
+            Opts::new("messages_received_total", "Messages received from NATS"),
+            &["subject"],
+        )
+        .unwrap();
+        let rows_flushed = IntCounterVec::new(
+            Opts::new("rows_flushed_total", "Rows inserted into ClickHouse"),
+            &["table"],
+        )
+        .unwrap();
+        let bytes_flushed = IntCounterVec::new(
+            Opts::new(
+                "bytes_flushed_total",
+                "Payload bytes inserted into ClickHouse",
+            ),
+            &["table"],
+        )
+        .unwrap();
+        let flush_duration = HistogramVec::new(
+            HistogramOpts::new("flush_duration_seconds", "Time spent flushing a batch")
+                .buckets(exponential_buckets(0.005, 2.0, 14).unwrap()),
+            &["table"],
+        )
+        .unwrap();
+        let flush_failures = IntCounterVec::new(
+            Opts::new("flush_failures_total", "Failed ClickHouse inserts"),
+            &["table", "class"],
+        )
+        .unwrap();
+        let batch_rows = HistogramVec::new(
+            HistogramOpts::new("batch_rows", "Rows per batch at flush time")
+                .buckets(exponential_buckets(1.0, 4.0, 11).unwrap()),
+            &["table"],
+        )
+        .unwrap();
+        let acks = IntCounterVec::new(
+            Opts::new("acks_total", "Acknowledgements sent to JetStream"),
+            &["kind"],
+        )
+        .unwrap();
+        let ack_failures = IntCounterVec::new(
+            Opts::new(
+                "ack_failures_total",
+                "Acknowledgements that could not be sent to JetStream",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+        let channel_depth = IntGauge::new(
+            "channel_depth",
+            "Messages queued between the consumers and the batcher",
+        )
+        .unwrap();
+        let flushes_in_flight = IntGauge::new(
+            "flushes_in_flight",
+            "Flushes currently inserting into ClickHouse",
+        )
+        .unwrap();
+        let payloads_rejected = IntCounterVec::new(
+            Opts::new(
+                "payloads_rejected_total",
+                "Payloads dead-lettered before batching",
+            ),
+            &["subject"],
+        )
+        .unwrap();
+        let clickhouse_responses = IntCounterVec::new(
+            Opts::new("clickhouse_responses_total", "ClickHouse HTTP responses"),
+            &["status"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(messages_received.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(payloads_rejected.clone()))
+            .unwrap();
+        registry.register(Box::new(rows_flushed.clone())).unwrap();
+        registry.register(Box::new(bytes_flushed.clone())).unwrap();
+        registry.register(Box::new(flush_duration.clone())).unwrap();
+        registry.register(Box::new(flush_failures.clone())).unwrap();
+        registry.register(Box::new(batch_rows.clone())).unwrap();
+        registry.register(Box::new(acks.clone())).unwrap();
+        registry.register(Box::new(ack_failures.clone())).unwrap();
+        registry.register(Box::new(channel_depth.clone())).unwrap();
+        registry
+            .register(Box::new(flushes_in_flight.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(clickhouse_responses.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            messages_received,
+            payloads_rejected,
+            rows_flushed,
+            bytes_flushed,
+            flush_duration,
+            flush_failures,
+            batch_rows,
+            acks,
+            ack_failures,
+            channel_depth,
+            flushes_in_flight,
+            clickhouse_responses,
+        }
+    }
+
+    pub fn ack(&self, kind: &str) {
+        self.acks.with_label_values(&[kind]).inc();
+    }
+
+    pub fn ack_failed(&self, kind: &str) {
+        self.ack_failures.with_label_values(&[kind]).inc();
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn encode(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("encode metrics");
+        String::from_utf8(buf).expect("metrics are utf-8")
+    }
+}
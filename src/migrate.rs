@@ -0,0 +1,450 @@
+use crate::cli::MigrateCommand;
+use crate::click_house::{ClickHouseClient, quote};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const MIGRATIONS_TABLE: &str = "schema_migrations";
+
+/// A numbered migration, e.g. `004_create_sabte_ahval_events_table.up.sql`
+/// with an optional matching `.down.sql`.
+struct Migration {
+    version: u64,
+    name: String,
+    up: PathBuf,
+    down: Option<PathBuf>,
+}
+
+impl Migration {
+    fn checksum(&self) -> Result<String, anyhow::Error> {
+        let sql = std::fs::read(&self.up)?;
+        Ok(Sha256::digest(&sql)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect())
+    }
+}
+
+struct Applied {
+    name: String,
+    checksum: String,
+}
+
+fn discover(dir: &Path) -> Result<Vec<Migration>, anyhow::Error> {
+    let mut by_version: HashMap<u64, Migration> = HashMap::new();
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| anyhow::anyhow!("read migrations dir {}: {}", dir.display(), e))?;
+
+    for entry in entries {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let (stem, is_up) = if let Some(stem) = file_name.strip_suffix(".up.sql") {
+            (stem, true)
+        } else if let Some(stem) = file_name.strip_suffix(".down.sql") {
+            (stem, false)
+        } else {
+            continue;
+        };
+        let Some((version, name)) = stem.split_once('_') else {
+            return Err(anyhow::anyhow!(
+                "migration {} has no version prefix",
+                file_name
+            ));
+        };
+        let version: u64 = version
+            .parse()
+            .map_err(|_| anyhow::anyhow!("migration {} has an invalid version", file_name))?;
+
+        let m = by_version.entry(version).or_insert_with(|| Migration {
+            version,
+            name: name.to_string(),
+            up: PathBuf::new(),
+            down: None,
+        });
+        if m.name != name {
+            return Err(anyhow::anyhow!(
+                "migration version {} is used by both `{}` and `{}`",
+                version,
+                m.name,
+                name
+            ));
+        }
+        if is_up {
+            m.up = path;
+        } else {
+            m.down = Some(path);
+        }
+    }
+
+    let mut migrations: Vec<Migration> = by_version.into_values().collect();
+    if let Some(m) = migrations.iter().find(|m| m.up.as_os_str().is_empty()) {
+        return Err(anyhow::anyhow!(
+            "migration {:03}_{} has a .down.sql but no .up.sql",
+            m.version,
+            m.name
+        ));
+    }
+    migrations.sort_by_key(|m| m.version);
+    Ok(migrations)
+}
+
+/// Splits a migration file into individual statements; ClickHouse accepts
+/// only one statement per HTTP request. Semicolons inside quoted strings,
+/// quoted identifiers and `--` or `/* */` comments do not end a statement,
+/// and pieces holding nothing but comments are dropped.
+fn statements(sql: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut has_code = false;
+    let mut chars = sql.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\'' | '"' | '`' => {
+                has_code = true;
+                while let Some((_, d)) = chars.next() {
+                    if d == '\\' {
+                        chars.next();
+                    } else if d == c {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.next_if(|&(_, d)| d == '-').is_some() => {
+                while chars.next_if(|&(_, d)| d != '\n').is_some() {}
+            }
+            '/' if chars.next_if(|&(_, d)| d == '*').is_some() => {
+                let mut prev = ' ';
+                for (_, d) in chars.by_ref() {
+                    if prev == '*' && d == '/' {
+                        break;
+                    }
+                    prev = d;
+                }
+            }
+            ';' => {
+                if has_code {
+                    statements.push(sql[start..i].trim());
+                }
+                start = i + 1;
+                has_code = false;
+            }
+            c if !c.is_whitespace() => has_code = true,
+            _ => {}
+        }
+    }
+    if has_code {
+        statements.push(sql[start..].trim());
+    }
+    statements
+}
+
+async fn ensure_table(ch: &ClickHouseClient) -> Result<(), anyhow::Error> {
+    ch.query(&format!(
+        "CREATE TABLE IF NOT EXISTS {MIGRATIONS_TABLE} (
+            version UInt64,
+            name String,
+            checksum String,
+            applied UInt8,
+            updated_at DateTime64(6) DEFAULT now64(6)
+        ) ENGINE = ReplacingMergeTree(updated_at)
+        ORDER BY version"
+    ))
+    .await?;
+    Ok(())
+}
+
+async fn applied(ch: &ClickHouseClient) -> Result<HashMap<u64, Applied>, anyhow::Error> {
+    let text = ch
+        .query(&format!(
+            "SELECT version, argMax(name, updated_at), argMax(checksum, updated_at), \
+             argMax(applied, updated_at) AS is_applied \
+             FROM {MIGRATIONS_TABLE} GROUP BY version HAVING is_applied = 1 \
+             FORMAT TabSeparated"
+        ))
+        .await?;
+
+    let mut applied = HashMap::new();
+    for line in text.lines() {
+        let cols: Vec<&str> = line.split('\t').collect();
+        if let [version, name, checksum, _] = cols[..] {
+            applied.insert(
+                version.parse()?,
+                Applied {
+                    name: name.to_string(),
+                    checksum: checksum.to_string(),
+                },
+            );
+        }
+    }
+    Ok(applied)
+}
+
+async fn record(
+    ch: &ClickHouseClient,
+    m: &Migration,
+    checksum: &str,
+    applied: bool,
+) -> Result<(), anyhow::Error> {
+    ch.query(&format!(
+        "INSERT INTO {MIGRATIONS_TABLE} (version, name, checksum, applied) VALUES ({}, {}, {}, {})",
+        m.version,
+        quote(&m.name),
+        quote(checksum),
+        applied as u8
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Fails if an already applied migration was edited afterwards.
+fn verify_checksums(
+    migrations: &[Migration],
+    applied: &HashMap<u64, Applied>,
+) -> Result<(), anyhow::Error> {
+    for m in migrations {
+        if let Some(a) = applied.get(&m.version) {
+            let checksum = m.checksum()?;
+            if a.checksum != checksum {
+                return Err(anyhow::anyhow!(
+                    "migration {:03}_{} was modified after being applied (checksum {} != recorded {})",
+                    m.version,
+                    m.name,
+                    checksum,
+                    a.checksum
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn up(ch: &ClickHouseClient, dir: &Path) -> Result<(), anyhow::Error> {
+    let migrations = discover(dir)?;
+    let applied = applied(ch).await?;
+    verify_checksums(&migrations, &applied)?;
+
+    let mut count = 0;
+    for m in migrations
+        .iter()
+        .filter(|m| !applied.contains_key(&m.version))
+    {
+        let checksum = m.checksum()?;
+        let sql = std::fs::read_to_string(&m.up)?;
+        info!("Applying migration {:03}_{}", m.version, m.name);
+        for stmt in statements(&sql) {
+            ch.query(stmt).await.map_err(|e| {
+                anyhow::anyhow!("migration {:03}_{} failed: {}", m.version, m.name, e)
+            })?;
+        }
+        record(ch, m, &checksum, true).await?;
+        count += 1;
+    }
+    info!("Applied {} migration(s).", count);
+    Ok(())
+}
+
+async fn down(ch: &ClickHouseClient, dir: &Path, steps: usize) -> Result<(), anyhow::Error> {
+    let migrations = discover(dir)?;
+    let applied = applied(ch).await?;
+    verify_checksums(&migrations, &applied)?;
+
+    let mut versions: Vec<u64> = applied.keys().copied().collect();
+    versions.sort_unstable_by(|a, b| b.cmp(a));
+
+    for version in versions.into_iter().take(steps) {
+        let Some(m) = migrations.iter().find(|m| m.version == version) else {
+            return Err(anyhow::anyhow!(
+                "migration {:03}_{} is applied but its file is missing",
+                version,
+                applied[&version].name
+            ));
+        };
+        let Some(down) = &m.down else {
+            return Err(anyhow::anyhow!(
+                "migration {:03}_{} has no .down.sql",
+                m.version,
+                m.name
+            ));
+        };
+        let sql = std::fs::read_to_string(down)?;
+        info!("Rolling back migration {:03}_{}", m.version, m.name);
+        for stmt in statements(&sql) {
+            ch.query(stmt).await.map_err(|e| {
+                anyhow::anyhow!("rollback of {:03}_{} failed: {}", m.version, m.name, e)
+            })?;
+        }
+        record(ch, m, &applied[&version].checksum, false).await?;
+    }
+    Ok(())
+}
+
+async fn status(ch: &ClickHouseClient, dir: &Path) -> Result<(), anyhow::Error> {
+    let migrations = discover(dir)?;
+    let applied = applied(ch).await?;
+
+    for m in &migrations {
+        let state = match applied.get(&m.version) {
+            None => "pending",
+            Some(a) if a.checksum != m.checksum()? => "modified",
+            Some(_) => "applied",
+        };
+        println!("{:03}  {:<9} {}", m.version, state, m.name);
+    }
+    for (version, a) in &applied {
+        if !migrations.iter().any(|m| m.version == *version) {
+            println!("{:03}  {:<9} {}", version, "missing", a.name);
+        }
+    }
+    Ok(())
+}
+
+/// Entry point for `forghoon migrate`.
+pub async fn run(
+    ch: &ClickHouseClient,
+    dir: &Path,
+    command: &MigrateCommand,
+) -> Result<(), anyhow::Error> {
+    ensure_table(ch).await?;
+    match command {
+        MigrateCommand::Up => up(ch, dir).await,
+        MigrateCommand::Down { steps } => down(ch, dir, *steps).await,
+        MigrateCommand::Status => status(ch, dir).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory in the temp dir, removed with its files when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "forghoon-migrations-{}-{}",
+                std::process::id(),
+                name
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            for (file, sql) in files {
+                std::fs::write(path.join(file), sql).unwrap();
+            }
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn discover_err(name: &str, files: &[(&str, &str)]) -> String {
+        let dir = TempDir::new(name, files);
+        discover(&dir.0).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn discover_pairs_and_sorts_migrations() {
+        let dir = TempDir::new(
+            "pairs",
+            &[
+                ("010_b.up.sql", "SELECT 1"),
+                ("002_a.up.sql", "SELECT 1"),
+                ("002_a.down.sql", "SELECT 1"),
+                ("README.md", ""),
+            ],
+        );
+        let migrations = discover(&dir.0).unwrap();
+        let found: Vec<_> = migrations
+            .iter()
+            .map(|m| (m.version, m.name.as_str(), m.down.is_some()))
+            .collect();
+        assert_eq!(found, [(2, "a", true), (10, "b", false)]);
+        assert_eq!(migrations[0].up, dir.0.join("002_a.up.sql"));
+    }
+
+    #[test]
+    fn discover_rejects_inconsistent_files() {
+        let e = discover_err("prefix", &[("create.up.sql", "")]);
+        assert!(e.contains("has no version prefix"), "{}", e);
+        let e = discover_err("version", &[("x1_create.up.sql", "")]);
+        assert!(e.contains("has an invalid version"), "{}", e);
+        let e = discover_err("reused", &[("001_a.up.sql", ""), ("001_b.up.sql", "")]);
+        assert!(e.contains("migration version 1 is used by both"), "{}", e);
+        let e = discover_err("orphan", &[("003_a.down.sql", "")]);
+        assert!(e.contains("003_a has a .down.sql but no .up.sql"), "{}", e);
+        assert!(discover(Path::new("/nonexistent/forghoon")).is_err());
+    }
+
+    #[test]
+    fn statements_split_on_semicolons() {
+        assert_eq!(
+            statements("CREATE TABLE a (x UInt8);\n\nDROP TABLE b;  \n"),
+            ["CREATE TABLE a (x UInt8)", "DROP TABLE b"]
+        );
+        assert_eq!(statements("SELECT 1"), ["SELECT 1"]);
+        assert!(statements(" ;\n; ").is_empty());
+    }
+
+    #[test]
+    fn statements_ignore_semicolons_in_strings_and_comments() {
+        let sql = "-- first; still a comment\n\
+                   INSERT INTO t VALUES ('a;b', 'it''s;', 'x\\';y');\n\
+                   SELECT \"c;d\", `e;f` /* g; h */ FROM t;\n\
+                   -- trailing; comment\n";
+        assert_eq!(
+            statements(sql),
+            [
+                "-- first; still a comment\nINSERT INTO t VALUES ('a;b', 'it''s;', 'x\\';y')",
+                "SELECT \"c;d\", `e;f` /* g; h */ FROM t",
+            ]
+        );
+    }
+
+    #[test]
+    fn shipped_migrations_split_cleanly() {
+        let migrations = discover(Path::new("migrations")).unwrap();
+        for m in &migrations {
+            for path in std::iter::once(&m.up).chain(&m.down) {
+                let sql = std::fs::read_to_string(path).unwrap();
+                let parts = statements(&sql);
+                assert!(!parts.is_empty(), "{}", path.display());
+                assert_eq!(parts.len(), sql.matches(';').count(), "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn edited_migrations_fail_the_checksum() {
+        let dir = TempDir::new(
+            "checksums",
+            &[("001_a.up.sql", "SELECT 1"), ("002_b.up.sql", "")],
+        );
+        let migrations = discover(&dir.0).unwrap();
+        let applied = |checksum: String| {
+            HashMap::from([(
+                1,
+                Applied {
+                    name: "a".to_string(),
+                    checksum,
+                },
+            )])
+        };
+        let checksum = migrations[0].checksum().unwrap();
+        assert!(verify_checksums(&migrations, &applied(checksum)).is_ok());
+        let e = verify_checksums(&migrations, &applied("0".repeat(64)))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.contains("migration 001_a was modified after being applied"),
+            "{}",
+            e
+        );
+        assert!(verify_checksums(&migrations, &HashMap::new()).is_ok());
+    }
+}
@@ -1,87 +1,330 @@
-use std::time::Duration;
 use crate::config;
-use async_nats::jetstream::consumer::AckPolicy;
+use crate::error::ClickHouseError;
+use crate::metrics::METRICS;
+use async_nats::connection::State;
+use async_nats::jetstream::ErrorCode;
 use async_nats::jetstream::consumer::pull::{Config as PullConfig, Stream as Messages};
-use async_nats::jetstream::stream;
+use async_nats::jetstream::consumer::{AckPolicy, IntoConsumerConfig};
+use async_nats::jetstream::context::{ConsumerInfoErrorKind, GetStreamError, GetStreamErrorKind};
+use async_nats::jetstream::message::AckKind;
+use async_nats::jetstream::{Message, stream};
 use async_nats::{Client, ConnectOptions};
 use futures::StreamExt;
+use std::time::Duration;
 use tokio::sync::mpsc;
 use tracing::{info, warn};
 
+mod reconcile;
+
+pub const HEADER_ORIGINAL_SUBJECT: &str = "Forghoon-Original-Subject";
+pub const HEADER_ERROR: &str = "Forghoon-Error";
+pub const HEADER_CLICKHOUSE_STATUS: &str = "Forghoon-ClickHouse-Status";
+pub const HEADER_CLICKHOUSE_CODE: &str = "Forghoon-ClickHouse-Exception-Code";
+pub const HEADER_DELIVERY_COUNT: &str = "Forghoon-Delivery-Count";
+pub const HEADER_STREAM_SEQUENCE: &str = "Forghoon-Stream-Sequence";
+
 pub struct Nats {
     client: Client,
     js: async_nats::jetstream::Context,
     stream_name: String,
     subjects: Vec<String>,
     consumer_name: String,
+    consumer_config: config::ConsumerConfig,
+    dead_letter: Option<DeadLetter>,
+    monitoring_addr: String,
+    monitoring: reqwest::Client,
+}
+
+/// The stream as configured, used both to create it and to check an
+/// existing one.
+fn stream_config(nats_config: &config::NatsConfig) -> stream::Config {
+    stream::Config {
+        name: nats_config.stream_config.name.clone(),
+        subjects: nats_config.subjects.clone(),
+        retention: nats_config.stream_config.retention,
+        discard: nats_config.stream_config.discard,
+        storage: nats_config.stream_config.storage,
+        max_consumers: nats_config.stream_config.max_consumers as i32,
+        max_age: nats_config.stream_config.max_age(),
+        no_ack: nats_config.stream_config.no_ack,
+        num_replicas: nats_config.stream_config.replicas,
+        max_messages: nats_config.stream_config.max_msgs,
+        max_bytes: nats_config.stream_config.max_bytes,
+        duplicate_window: nats_config.stream_config.duplicate_window(),
+        compression: Some(nats_config.stream_config.compression.clone()),
+        ..Default::default()
+    }
+}
+
+/// Whether the server answered that the stream does not exist, as opposed
+/// to the lookup failing (timeout, missing permissions, ...).
+fn is_stream_not_found(e: &GetStreamError) -> bool {
+    matches!(
+        e.kind(),
+        GetStreamErrorKind::JetStream(e) if e.error_code() == ErrorCode::STREAM_NOT_FOUND
+    )
+}
+
+/// Publishes poison messages to the dead-letter subject so they can be
+/// inspected and replayed later.
+#[derive(Clone)]
+pub struct DeadLetter {
+    js: async_nats::jetstream::Context,
+    subject: String,
+}
+
+impl DeadLetter {
+    /// Republishes the original payload and headers, annotated with why the
+    /// message was rejected, and waits for the stream to acknowledge it.
+    pub async fn publish(
+        &self,
+        msg: &Message,
+        error: &str,
+        clickhouse_error: Option<&ClickHouseError>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut headers = msg.headers.clone().unwrap_or_default();
+        headers.insert(HEADER_ORIGINAL_SUBJECT, msg.subject.as_str());
+        // Header values may not contain line breaks.
+        headers.insert(HEADER_ERROR, error.replace(['\r', '\n'], " "));
+        if let Some(status) = clickhouse_error.and_then(ClickHouseError::status) {
+            headers.insert(HEADER_CLICKHOUSE_STATUS, status.as_u16().to_string());
+        }
+        if let Some(code) = clickhouse_error.and_then(ClickHouseError::code) {
+            headers.insert(HEADER_CLICKHOUSE_CODE, code.to_string());
+        }
+        if let Ok(info) = msg.info() {
+            headers.insert(HEADER_DELIVERY_COUNT, info.delivered.to_string());
+            headers.insert(HEADER_STREAM_SEQUENCE, info.stream_sequence.to_string());
+        }
+
+        self.js
+            .publish_with_headers(self.subject.clone(), headers, msg.payload.clone())
+            .await?
+            .await?;
+        Ok(())
+    }
+}
+
+/// Terminates a message that can never be processed, copying it to the
+/// dead-letter subject first when one is configured. If that copy fails the
+/// message is NAK'd instead so it is not lost.
+pub async fn term_message(
+    dead_letter: Option<&DeadLetter>,
+    msg: &Message,
+    error: &str,
+    clickhouse_error: Option<&ClickHouseError>,
+) {
+    if let Some(dl) = dead_letter
+        && let Err(e) = dl.publish(msg, error, clickhouse_error).await
+    {
+        warn!(
+            "dead-letter publish failed for {}: {}; NAK instead",
+            msg.subject, e
+        );
+        if let Err(e) = ack_with(msg, AckKind::Nak(None), "nak").await {
+            warn!("NAK for {} failed: {}", msg.subject, e);
+        }
+        return;
+    }
+    if let Err(e) = ack_with(msg, AckKind::Term, "term").await {
+        warn!("TERM for {} failed: {}", msg.subject, e);
+    }
+}
+
+/// Sends `kind` for `msg`, counting it under `label` in `acks_total` or
+/// `ack_failures_total`.
+pub async fn ack_with(msg: &Message, kind: AckKind, label: &str) -> Result<(), async_nats::Error> {
+    let result = msg.ack_with(kind).await;
+    match &result {
+        Ok(()) => METRICS.ack(label),
+        Err(_) => METRICS.ack_failed(label),
+    }
+    result
 }
 
 impl Nats {
     pub async fn new(nats_config: config::NatsConfig) -> Result<Self, Box<dyn std::error::Error>> {
-        let client = ConnectOptions::with_user_and_password(
-            nats_config.username.clone(),
-            nats_config.password.clone(),
-        )
-        .connect(nats_config.get_addr())
-        .await?;
+        let options = if nats_config.username.is_empty() {
+            ConnectOptions::new()
+        } else {
+            ConnectOptions::with_user_and_password(
+                nats_config.username.clone(),
+                nats_config.password.clone(),
+            )
+        };
+        let client = options
+            .name(nats_config.consumer_name.clone())
+            .connect(nats_config.get_addr())
+            .await?;
+
+        if nats_config.queue.is_some() {
+            warn!(
+                "nats.queue is deprecated and ignored; instances share work through the durable consumer `{}`",
+                nats_config.consumer_name
+            );
+        }
 
+        let monitoring_addr = nats_config.get_monitoring_addr();
         let js = async_nats::jetstream::new(client.clone());
+        let desired = stream_config(&nats_config);
         match js.get_stream(nats_config.stream_config.name.clone()).await {
-            Ok(_) => {}
-            Err(e) => {
-                if e.to_string().contains("stream not found")
-                    || nats_config.stream_config.need_create()
-                {
-                    info!(
-                        "stream not found, creating stream: {}",
-                        nats_config.stream_config.name
-                    );
-
-                    js.create_stream(stream::Config {
-                        name: nats_config.stream_config.name.clone(),
-                        subjects: nats_config.subjects.clone(),
-                        retention: nats_config.stream_config.retention,
-                        discard: nats_config.stream_config.discard,
-                        storage: nats_config.stream_config.storage,
-                        max_consumers: nats_config.stream_config.max_consumers as i32,
-                        ..Default::default()
-                    })
+            Ok(stream) => {
+                let what = format!("stream `{}`", desired.name);
+                let diffs = reconcile::stream_diff(&stream.cached_info().config, &desired);
+                if reconcile::should_update(
+                    &what,
+                    &diffs,
+                    nats_config.stream_config.update_existing,
+                )? {
+                    js.update_stream(&reconcile::stream_update(
+                        &stream.cached_info().config,
+                        &desired,
+                    ))
                     .await?;
+                    info!("updated {}", what);
                 }
             }
+            Err(e) if is_stream_not_found(&e) => {
+                if !nats_config.stream_config.need_create() {
+                    return Err(format!(
+                        "stream `{}` does not exist and need_create is false",
+                        desired.name
+                    )
+                    .into());
+                }
+                info!("stream not found, creating stream: {}", desired.name);
+                js.create_stream(desired).await?;
+            }
+            Err(e) => {
+                return Err(format!("cannot look up stream `{}`: {}", desired.name, e).into());
+            }
         }
 
+        let dead_letter = match nats_config.dead_letter {
+            Some(dl) => {
+                match js.get_stream(dl.stream.clone()).await {
+                    Ok(_) => {}
+                    Err(e) if is_stream_not_found(&e) => {
+                        info!("creating dead-letter stream: {}", dl.stream);
+                        js.create_stream(stream::Config {
+                            name: dl.stream.clone(),
+                            subjects: vec![dl.subject.clone()],
+                            storage: stream::StorageType::File,
+                            ..Default::default()
+                        })
+                        .await?;
+                    }
+                    Err(e) => {
+                        return Err(format!("cannot look up stream `{}`: {}", dl.stream, e).into());
+                    }
+                }
+                Some(DeadLetter {
+                    js: js.clone(),
+                    subject: dl.subject,
+                })
+            }
+            None => None,
+        };
+
         Ok(Nats {
+            monitoring_addr,
             client,
             js,
+            dead_letter,
             stream_name: nats_config.stream_config.name,
             subjects: nats_config.subjects,
             consumer_name: nats_config.consumer_name,
+            consumer_config: nats_config.consumer,
+            monitoring: reqwest::Client::builder()
+                .timeout(Duration::from_secs(3))
+                .build()?,
         })
     }
 
+    pub fn is_connected(&self) -> bool {
+        self.client.connection_state() == State::Connected
+    }
+
+    /// Largest message the server accepts.
+    pub fn max_payload(&self) -> usize {
+        self.client.server_info().max_payload
+    }
+
+    /// Whether the durable consumer is present on the stream.
+    pub async fn consumer_exists(&self) -> bool {
+        match self.js.get_stream(&self.stream_name).await {
+            Ok(stream) => stream.consumer_info(&self.consumer_name).await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the server's monitoring endpoint reports it healthy with
+    /// JetStream enabled.
+    pub async fn server_healthy(&self) -> bool {
+        let url = format!("{}/healthz?js-enabled-only=true", self.monitoring_addr);
+        match self.monitoring.get(url).send().await {
+            Ok(resp) => resp.status().is_success(),
+            Err(e) => {
+                warn!("NATS monitoring endpoint unreachable: {}", e);
+                false
+            }
+        }
+    }
+
+    pub fn dead_letter(&self) -> Option<DeadLetter> {
+        self.dead_letter.clone()
+    }
+
+    /// Creates the durable consumer, or checks an existing one against the
+    /// configuration and updates the settings that differ.
     pub async fn consume(&self) -> Result<Messages, Box<dyn std::error::Error>> {
-        let consumer = self
-            .js
-            .create_consumer_on_stream(
-                PullConfig {
-                    durable_name: Some(self.consumer_name.clone()),
-                    filter_subjects: self.subjects.clone(),
-                    ack_policy: AckPolicy::Explicit,
-                    ack_wait: Duration::from_secs(120),
-                    max_ack_pending: 200_000,
-                    max_bytes: 5_000_000,
-                    max_deliver: 3,
-                    ..Default::default()
-                },
-                self.stream_name.clone(),
-            )
-            .await?;
+        let config = self.pull_config();
+        let stream = self.js.get_stream(&self.stream_name).await?;
+        let consumer = match stream.consumer_info(&self.consumer_name).await {
+            Ok(info) => {
+                let what = format!("consumer `{}`", self.consumer_name);
+                let desired = config.into_consumer_config();
+                let diffs = reconcile::consumer_diff(&info.config, &desired);
+                if reconcile::should_update(&what, &diffs, self.consumer_config.update_existing)? {
+                    stream
+                        .update_consumer(reconcile::consumer_update(&info.config, &desired))
+                        .await?;
+                    info!("updated {}", what);
+                }
+                stream
+                    .get_consumer::<PullConfig>(&self.consumer_name)
+                    .await
+                    .map_err(|e| -> Box<dyn std::error::Error> { e })?
+            }
+            Err(e) if e.kind() == ConsumerInfoErrorKind::NotFound => {
+                stream.create_consumer(config).await?
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         let stream_messages = consumer.messages().await?;
         Ok(stream_messages)
     }
 
+    fn pull_config(&self) -> PullConfig {
+        PullConfig {
+            durable_name: Some(self.consumer_name.clone()),
+            filter_subjects: self.subjects.clone(),
+            ack_policy: AckPolicy::Explicit,
+            ack_wait: self.consumer_config.ack_wait(),
+            max_ack_pending: self.consumer_config.max_ack_pending,
+            max_bytes: self.consumer_config.max_bytes,
+            max_deliver: self.consumer_config.max_deliver,
+            backoff: self.consumer_config.backoff(),
+            deliver_policy: self.consumer_config.deliver_policy(),
+            inactive_threshold: self.consumer_config.inactive_threshold(),
+            max_waiting: self.consumer_config.max_waiting,
+            replay_policy: self.consumer_config.replay_policy,
+            ..Default::default()
+        }
+    }
+
+    #[allow(dead_code)]
     pub async fn consume_to_channel(
         &self,
         tx: mpsc::Sender<async_nats::jetstream::Message>,
@@ -0,0 +1,341 @@
+use async_nats::jetstream::{consumer, stream};
+use std::collections::BTreeSet;
+use std::fmt;
+use tracing::warn;
+
+/// A setting whose live value on the server differs from the configured one.
+pub struct Difference {
+    field: &'static str,
+    live: String,
+    desired: String,
+    /// The server refuses to change this setting on an existing stream or
+    /// consumer.
+    immutable: bool,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.live, self.desired)?;
+        if self.immutable {
+            write!(f, " (cannot be changed in place)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Diff(Vec<Difference>);
+
+impl Diff {
+    fn field<T: fmt::Debug + PartialEq>(&mut self, field: &'static str, live: T, desired: T) {
+        self.push(field, live, desired, false);
+    }
+
+    fn immutable<T: fmt::Debug + PartialEq>(&mut self, field: &'static str, live: T, desired: T) {
+        self.push(field, live, desired, true);
+    }
+
+    fn push<T: fmt::Debug + PartialEq>(
+        &mut self,
+        field: &'static str,
+        live: T,
+        desired: T,
+        immutable: bool,
+    ) {
+        if live != desired {
+            self.0.push(Difference {
+                field,
+                live: format!("{:?}", live),
+                desired: format!("{:?}", desired),
+                immutable,
+            });
+        }
+    }
+}
+
+/// Differences in the stream settings this service configures.
+pub fn stream_diff(live: &stream::Config, desired: &stream::Config) -> Vec<Difference> {
+    let mut diff = Diff::default();
+    diff.field(
+        "subjects",
+        live.subjects.iter().collect::<BTreeSet<_>>(),
+        desired.subjects.iter().collect(),
+    );
+    diff.immutable("retention", live.retention, desired.retention);
+    diff.field("discard", live.discard, desired.discard);
+    diff.immutable("storage", live.storage, desired.storage);
+    diff.immutable("max_consumers", live.max_consumers, desired.max_consumers);
+    diff.field("max_age", live.max_age, desired.max_age);
+    diff.field("no_ack", live.no_ack, desired.no_ack);
+    diff.field("num_replicas", live.num_replicas, desired.num_replicas);
+    diff.field("max_messages", live.max_messages, desired.max_messages);
+    diff.field("max_bytes", live.max_bytes, desired.max_bytes);
+    diff.field(
+        "duplicate_window",
+        live.duplicate_window,
+        desired.duplicate_window,
+    );
+    // Servers report an unset compression as "none".
+    diff.field(
+        "compression",
+        live.compression
+            .clone()
+            .unwrap_or(stream::Compression::None),
+        desired
+            .compression
+            .clone()
+            .unwrap_or(stream::Compression::None),
+    );
+    diff.0
+}
+
+/// `live` with the settings compared by [`stream_diff`] taken from `desired`.
+/// Updates are built from this, so settings managed elsewhere (sources,
+/// placement, direct get, ...) are sent back unchanged instead of reset.
+pub fn stream_update(live: &stream::Config, desired: &stream::Config) -> stream::Config {
+    stream::Config {
+        subjects: desired.subjects.clone(),
+        retention: desired.retention,
+        discard: desired.discard,
+        storage: desired.storage,
+        max_consumers: desired.max_consumers,
+        max_age: desired.max_age,
+        no_ack: desired.no_ack,
+        num_replicas: desired.num_replicas,
+        max_messages: desired.max_messages,
+        max_bytes: desired.max_bytes,
+        duplicate_window: desired.duplicate_window,
+        compression: desired.compression.clone(),
+        ..live.clone()
+    }
+}
+
+/// Differences in the consumer settings this service configures.
+pub fn consumer_diff(live: &consumer::Config, desired: &consumer::Config) -> Vec<Difference> {
+    let mut diff = Diff::default();
+    diff.field("filter_subjects", filters(live), filters(desired));
+    diff.immutable("ack_policy", live.ack_policy, desired.ack_policy);
+    diff.field("ack_wait", live.ack_wait, desired.ack_wait);
+    diff.field(
+        "max_ack_pending",
+        live.max_ack_pending,
+        desired.max_ack_pending,
+    );
+    diff.field("max_bytes", live.max_bytes, desired.max_bytes);
+    diff.field("max_deliver", live.max_deliver, desired.max_deliver);
+    diff.field("backoff", &live.backoff, &desired.backoff);
+    diff.immutable(
+        "deliver_policy",
+        live.deliver_policy,
+        desired.deliver_policy,
+    );
+    diff.field(
+        "inactive_threshold",
+        live.inactive_threshold,
+        desired.inactive_threshold,
+    );
+    diff.immutable("max_waiting", live.max_waiting, desired.max_waiting);
+    diff.immutable("replay_policy", live.replay_policy, desired.replay_policy);
+    diff.0
+}
+
+/// `live` with the settings compared by [`consumer_diff`] taken from
+/// `desired`, like [`stream_update`].
+pub fn consumer_update(live: &consumer::Config, desired: &consumer::Config) -> consumer::Config {
+    consumer::Config {
+        filter_subject: desired.filter_subject.clone(),
+        filter_subjects: desired.filter_subjects.clone(),
+        ack_policy: desired.ack_policy,
+        ack_wait: desired.ack_wait,
+        max_ack_pending: desired.max_ack_pending,
+        max_bytes: desired.max_bytes,
+        max_deliver: desired.max_deliver,
+        backoff: desired.backoff.clone(),
+        deliver_policy: desired.deliver_policy,
+        inactive_threshold: desired.inactive_threshold,
+        max_waiting: desired.max_waiting,
+        replay_policy: desired.replay_policy,
+        ..live.clone()
+    }
+}
+
+/// Filter subjects regardless of whether the single or the list form is used.
+fn filters(config: &consumer::Config) -> BTreeSet<&str> {
+    config
+        .filter_subjects
+        .iter()
+        .map(String::as_str)
+        .chain(Some(config.filter_subject.as_str()).filter(|s| !s.is_empty()))
+        .collect()
+}
+
+/// Logs the differences found on `what` (e.g. "stream `events`") and decides
+/// whether to apply them: `Ok(true)` to update, `Ok(false)` when nothing
+/// differs, and an error when updating is disabled or impossible.
+pub fn should_update(
+    what: &str,
+    diffs: &[Difference],
+    update_existing: bool,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if diffs.is_empty() {
+        return Ok(false);
+    }
+    for d in diffs {
+        warn!("{} differs from the configuration: {}", what, d);
+    }
+    let summary = diffs
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ");
+    if !update_existing {
+        return Err(format!(
+            "{} differs from the configuration ({}); set update_existing = true to apply it",
+            what, summary
+        )
+        .into());
+    }
+    if diffs.iter().any(|d| d.immutable) {
+        return Err(format!(
+            "{} cannot be updated in place ({}); delete it or change the configuration",
+            what, summary
+        )
+        .into());
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn stream() -> stream::Config {
+        stream::Config {
+            name: "events".to_string(),
+            subjects: vec!["events.a".to_string(), "events.b".to_string()],
+            max_age: Duration::from_secs(3600),
+            compression: Some(stream::Compression::None),
+            ..Default::default()
+        }
+    }
+
+    fn consumer() -> consumer::Config {
+        consumer::Config {
+            durable_name: Some("ingester".to_string()),
+            filter_subjects: vec!["events.a".to_string()],
+            ack_policy: consumer::AckPolicy::Explicit,
+            ack_wait: Duration::from_secs(120),
+            ..Default::default()
+        }
+    }
+
+    fn fields(diffs: &[Difference]) -> Vec<(&str, bool)> {
+        diffs.iter().map(|d| (d.field, d.immutable)).collect()
+    }
+
+    #[test]
+    fn equal_streams_have_no_differences() {
+        let mut live = stream();
+        live.subjects.reverse();
+        live.compression = None;
+        assert!(stream_diff(&live, &stream()).is_empty());
+    }
+
+    #[test]
+    fn stream_differences_are_listed_with_their_mutability() {
+        let live = stream::Config {
+            storage: stream::StorageType::Memory,
+            max_age: Duration::from_secs(60),
+            ..stream()
+        };
+        assert_eq!(
+            fields(&stream_diff(&live, &stream())),
+            [("storage", true), ("max_age", false)]
+        );
+    }
+
+    #[test]
+    fn stream_updates_keep_unmanaged_settings() {
+        let live = stream::Config {
+            max_age: Duration::from_secs(60),
+            allow_direct: true,
+            max_messages_per_subject: 10,
+            deny_delete: true,
+            metadata: HashMap::from([("owner".to_string(), "ops".to_string())]),
+            ..stream()
+        };
+        let update = stream_update(&live, &stream());
+        assert!(stream_diff(&update, &stream()).is_empty());
+        assert!(update.allow_direct);
+        assert_eq!(update.max_messages_per_subject, 10);
+        assert!(update.deny_delete);
+        assert_eq!(update.metadata["owner"], "ops");
+    }
+
+    #[test]
+    fn single_and_list_filters_are_equal() {
+        let live = consumer::Config {
+            filter_subject: "events.a".to_string(),
+            filter_subjects: Vec::new(),
+            ..consumer()
+        };
+        assert!(consumer_diff(&live, &consumer()).is_empty());
+    }
+
+    #[test]
+    fn consumer_differences_are_listed_with_their_mutability() {
+        let live = consumer::Config {
+            ack_wait: Duration::from_secs(30),
+            deliver_policy: consumer::DeliverPolicy::New,
+            ..consumer()
+        };
+        assert_eq!(
+            fields(&consumer_diff(&live, &consumer())),
+            [("ack_wait", false), ("deliver_policy", true)]
+        );
+    }
+
+    #[test]
+    fn consumer_updates_keep_unmanaged_settings() {
+        let live = consumer::Config {
+            ack_wait: Duration::from_secs(30),
+            description: Some("ingests events".to_string()),
+            headers_only: true,
+            ..consumer()
+        };
+        let update = consumer_update(&live, &consumer());
+        assert!(consumer_diff(&update, &consumer()).is_empty());
+        assert_eq!(update.description.as_deref(), Some("ingests events"));
+        assert!(update.headers_only);
+    }
+
+    #[test]
+    fn updates_need_differences_permission_and_mutability() {
+        let mutable = stream_diff(
+            &stream::Config {
+                max_age: Duration::from_secs(60),
+                ..stream()
+            },
+            &stream(),
+        );
+        let immutable = stream_diff(
+            &stream::Config {
+                storage: stream::StorageType::Memory,
+                ..stream()
+            },
+            &stream(),
+        );
+        assert!(!should_update("stream", &[], false).unwrap());
+        assert!(should_update("stream", &mutable, true).unwrap());
+        let err = should_update("stream", &mutable, false).unwrap_err();
+        assert!(err.to_string().contains("update_existing"), "{}", err);
+        let err = should_update("stream", &immutable, true).unwrap_err();
+        assert!(
+            err.to_string().contains("cannot be updated in place"),
+            "{}",
+            err
+        );
+    }
+}
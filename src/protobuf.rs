@@ -0,0 +1,757 @@
+use crate::config::Framing;
+use crate::schema::{ProtoField, ProtoFile, ProtoMessage};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Reads a base-128 varint, returning it and the number of bytes it took.
+/// `None` if it is truncated or does not fit in 64 bits.
+pub fn read_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &b) in buf.iter().enumerate().take(10) {
+        // The tenth byte holds only the top bit.
+        if i == 9 && b > 1 {
+            return None;
+        }
+        value |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+pub fn encode_varint(mut value: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(10);
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+    out
+}
+
+/// One top-level field of an encoded message.
+pub struct WireField<'a> {
+    pub number: u32,
+    pub wire_type: u8,
+    /// The encoded value after the tag, without the length of a
+    /// length-delimited field.
+    pub value: &'a [u8],
+}
+
+pub const WIRE_VARINT: u8 = 0;
+pub const WIRE_FIXED64: u8 = 1;
+pub const WIRE_LEN: u8 = 2;
+pub const WIRE_FIXED32: u8 = 5;
+
+impl WireField<'_> {
+    /// Whether the value is the type's default (zero, or empty), which
+    /// proto3 would not even have encoded.
+    pub fn is_default(&self) -> bool {
+        match self.wire_type {
+            WIRE_VARINT => read_varint(self.value).is_some_and(|(v, _)| v == 0),
+            _ => self.value.iter().all(|&b| b == 0),
+        }
+    }
+}
+
+/// Reads the field at the start of `buf` and the number of bytes it took,
+/// or `None` if it is malformed.
+fn read_field(buf: &[u8]) -> Option<(WireField<'_>, usize)> {
+    let (tag, n) = read_varint(buf)?;
+    let number = u32::try_from(tag >> 3).ok().filter(|&n| n != 0)?;
+    let wire_type = (tag & 7) as u8;
+    let rest = &buf[n..];
+    let (start, end) = match wire_type {
+        WIRE_VARINT => (0, read_varint(rest)?.1),
+        WIRE_FIXED64 => (0, 8),
+        WIRE_LEN => {
+            let (size, len) = read_varint(rest)?;
+            (len, len.checked_add(usize::try_from(size).ok()?)?)
+        }
+        WIRE_FIXED32 => (0, 4),
+        // Groups (3, 4) are deprecated and never produced for our schemas.
+        _ => return None,
+    };
+    let value = rest.get(start..end)?;
+    Some((
+        WireField {
+            number,
+            wire_type,
+            value,
+        },
+        n + end,
+    ))
+}
+
+/// The top-level fields of an encoded message, or `None` if it is malformed.
+pub fn fields(mut buf: &[u8]) -> Option<Vec<WireField<'_>>> {
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let (field, len) = read_field(buf)?;
+        fields.push(field);
+        buf = &buf[len..];
+    }
+    Some(fields)
+}
+
+/// Whether `buf` parses as a sequence of well-formed top-level fields.
+pub fn is_well_formed(mut buf: &[u8]) -> bool {
+    while !buf.is_empty() {
+        match read_field(buf) {
+            Some((_, len)) => buf = &buf[len..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Splits a buffer of varint length-delimited messages, or `None` unless
+/// the frames cover it exactly.
+pub fn frames(mut buf: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut frames = Vec::new();
+    while !buf.is_empty() {
+        let (size, n) = read_varint(buf)?;
+        let end = n.checked_add(usize::try_from(size).ok()?)?;
+        frames.push(buf.get(n..end)?);
+        buf = &buf[end..];
+    }
+    Some(frames)
+}
+
+/// A payload ready for `FORMAT Protobuf`: the message(s) as received, plus
+/// the length prefix the ingester adds when the producer sent one bare
+/// message. Payloads of other formats are carried with no prefix.
+#[derive(Debug, Clone)]
+pub struct Framed {
+    pub prefix: Bytes,
+    pub payload: Bytes,
+    /// Rows the payload inserts: its protobuf messages, or 1 for payloads of
+    /// other formats, which are not looked into.
+    pub count: usize,
+}
+
+impl Framed {
+    /// A payload inserted as received.
+    pub fn raw(payload: Bytes) -> Self {
+        Self {
+            prefix: Bytes::new(),
+            payload,
+            count: 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.prefix.len() + self.payload.len()
+    }
+
+    /// Whether the payload is exactly one bare message, which ClickHouse can
+    /// take as is with `FORMAT ProtobufSingle`.
+    pub fn is_single(&self) -> bool {
+        !self.prefix.is_empty()
+    }
+
+    /// The messages in the payload.
+    pub fn messages(&self) -> Vec<&[u8]> {
+        if self.is_single() {
+            vec![&self.payload]
+        } else {
+            frames(&self.payload).unwrap_or_default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramingError {
+    /// `delimited`: the length prefixes do not cover the payload exactly, or
+    /// a framed message is malformed.
+    NotDelimited,
+    /// `single`: the payload is not one well-formed message.
+    NotMessage,
+    /// `auto`: neither of the above.
+    Unrecognized,
+}
+
+impl fmt::Display for FramingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FramingError::NotDelimited => {
+                write!(f, "payload is not a sequence of length-delimited messages")
+            }
+            FramingError::NotMessage => write!(f, "payload is not a well-formed protobuf message"),
+            FramingError::Unrecognized => write!(
+                f,
+                "payload is neither length-delimited nor a single well-formed protobuf message"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FramingError {}
+
+/// `payload` as received if it is a non-empty run of well-formed
+/// length-delimited messages.
+fn delimited(payload: Bytes) -> Option<Framed> {
+    let count = frames(&payload)
+        .filter(|frames| !frames.is_empty() && frames.iter().all(|m| is_well_formed(m)))?
+        .len();
+    Some(Framed {
+        prefix: Bytes::new(),
+        payload,
+        count,
+    })
+}
+
+fn single(payload: Bytes) -> Framed {
+    Framed {
+        prefix: Bytes::from(encode_varint(payload.len() as u64)),
+        payload,
+        count: 1,
+    }
+}
+
+/// Brings `payload` into the length-delimited form `FORMAT Protobuf` expects.
+/// `auto` tries `delimited` first: a bare message whose first bytes also
+/// read as a length covering the rest is very unlikely.
+pub fn frame(payload: Bytes, framing: Framing) -> Result<Framed, FramingError> {
+    match framing {
+        // An empty payload would be acked without inserting anything.
+        Framing::Delimited => delimited(payload).ok_or(FramingError::NotDelimited),
+        Framing::Single if is_well_formed(&payload) => Ok(single(payload)),
+        Framing::Single => Err(FramingError::NotMessage),
+        Framing::Auto => match delimited(payload.clone()) {
+            Some(framed) => Ok(framed),
+            None if is_well_formed(&payload) => Ok(single(payload)),
+            None => Err(FramingError::Unrecognized),
+        },
+    }
+}
+
+/// [`frame`], keeping only a reading whose messages all pass `schema`. A
+/// payload `auto` reads as delimited but that fails validation is checked
+/// as one bare message before it is rejected.
+pub fn frame_validated(
+    payload: Bytes,
+    framing: Framing,
+    schema: &MessageSchema,
+) -> Result<Framed, Rejection> {
+    let framed = frame(payload.clone(), framing)?;
+    let e = match schema.validate_all(&framed) {
+        Ok(()) => return Ok(framed),
+        Err(e) => e,
+    };
+    if framing == Framing::Auto && !framed.is_single() && is_well_formed(&payload) {
+        let framed = single(payload);
+        if schema.validate_all(&framed).is_ok() {
+            return Ok(framed);
+        }
+    }
+    Err(e.into())
+}
+
+/// Why a payload was refused before batching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    Framing(FramingError),
+    Payload(PayloadError),
+}
+
+impl From<FramingError> for Rejection {
+    fn from(e: FramingError) -> Self {
+        Rejection::Framing(e)
+    }
+}
+
+impl From<PayloadError> for Rejection {
+    fn from(e: PayloadError) -> Self {
+        Rejection::Payload(e)
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Framing(e) => e.fmt(f),
+            Rejection::Payload(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// The wire-level shape of a message type: which wire types each declared
+/// field may use, and which fields every payload must carry.
+#[derive(Debug)]
+pub struct MessageSchema {
+    name: String,
+    fields: HashMap<u32, (String, Vec<u8>)>,
+    required: Vec<(u32, String)>,
+}
+
+impl MessageSchema {
+    /// `required` names fields that must be present and non-default; names
+    /// the message does not declare are ignored.
+    pub fn new(file: &ProtoFile, name: &str, message: &ProtoMessage, required: &[String]) -> Self {
+        let fields = message
+            .fields
+            .iter()
+            .map(|f| (f.number, (f.name.clone(), wire_types(file, f))))
+            .collect();
+        let required = message
+            .fields
+            .iter()
+            .filter(|f| required.contains(&f.name))
+            .map(|f| (f.number, f.name.clone()))
+            .collect();
+        Self {
+            name: name.to_string(),
+            fields,
+            required,
+        }
+    }
+
+    /// Checks every message of `framed`.
+    pub fn validate_all(&self, framed: &Framed) -> Result<(), PayloadError> {
+        framed
+            .messages()
+            .into_iter()
+            .try_for_each(|m| self.validate(m))
+    }
+
+    /// Checks one encoded message. Fields the schema does not declare are
+    /// allowed, as protobuf allows them.
+    pub fn validate(&self, msg: &[u8]) -> Result<(), PayloadError> {
+        let fields = fields(msg).ok_or_else(|| PayloadError::Malformed(self.name.clone()))?;
+        for field in &fields {
+            if let Some((name, allowed)) = self.fields.get(&field.number)
+                && !allowed.contains(&field.wire_type)
+            {
+                return Err(PayloadError::WireType {
+                    message: self.name.clone(),
+                    field: name.clone(),
+                    wire_type: field.wire_type,
+                });
+            }
+        }
+        for (number, name) in &self.required {
+            if !fields
+                .iter()
+                .any(|f| f.number == *number && !f.is_default())
+            {
+                return Err(PayloadError::MissingField {
+                    message: self.name.clone(),
+                    field: name.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wire types a field of the declared type may be encoded with.
+fn wire_types(file: &ProtoFile, field: &ProtoField) -> Vec<u8> {
+    let scalar = match field.ty.as_str() {
+        "int32" | "int64" | "uint32" | "uint64" | "sint32" | "sint64" | "bool" => WIRE_VARINT,
+        "fixed64" | "sfixed64" | "double" => WIRE_FIXED64,
+        "fixed32" | "sfixed32" | "float" => WIRE_FIXED32,
+        ty if file.is_enum(ty) => WIRE_VARINT,
+        // string, bytes, nested messages and maps
+        _ => return vec![WIRE_LEN],
+    };
+    if field.repeated {
+        // Repeated scalars may be packed into one length-delimited field.
+        vec![scalar, WIRE_LEN]
+    } else {
+        vec![scalar]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayloadError {
+    Malformed(String),
+    WireType {
+        message: String,
+        field: String,
+        wire_type: u8,
+    },
+    MissingField {
+        message: String,
+        field: String,
+    },
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::Malformed(message) => {
+                write!(f, "payload is not a well-formed {} message", message)
+            }
+            PayloadError::WireType {
+                message,
+                field,
+                wire_type,
+            } => write!(
+                f,
+                "{}.{} has wire type {}, which its declared type cannot use",
+                message, field, wire_type
+            ),
+            PayloadError::MissingField { message, field } => {
+                write!(f, "{}.{} is required but missing or empty", message, field)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::{dto, parse_proto};
+
+    /// Encodes fields as `(number, value)`: strings as length-delimited,
+    /// integers as varints.
+    enum Value<'a> {
+        Str(&'a str),
+        Int(u64),
+    }
+
+    fn encode(fields: &[(u32, Value)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (number, value) in fields {
+            match value {
+                Value::Str(s) => {
+                    out.extend(encode_varint(u64::from(*number) << 3 | 2));
+                    out.extend(encode_varint(s.len() as u64));
+                    out.extend(s.as_bytes());
+                }
+                Value::Int(v) => {
+                    out.extend(encode_varint(u64::from(*number) << 3));
+                    out.extend(encode_varint(*v));
+                }
+            }
+        }
+        out
+    }
+
+    fn delimit(messages: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for m in messages {
+            out.extend(encode_varint(m.len() as u64));
+            out.extend(*m);
+        }
+        out
+    }
+
+    /// A `dto.LoginEvent`.
+    fn login_event(event_id: &str) -> Vec<u8> {
+        encode(&[
+            (1, Value::Str(event_id)),
+            (2, Value::Str("login")),
+            (3, Value::Str("user-42")),
+            (6, Value::Int(1_714_521_600_000)),
+            (8, Value::Str("web")),
+        ])
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for v in [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16_383,
+            16_384,
+            u32::MAX as u64,
+            u64::MAX,
+        ] {
+            let encoded = encode_varint(v);
+            assert_eq!(read_varint(&encoded), Some((v, encoded.len())), "{}", v);
+        }
+        assert_eq!(encode_varint(300), [0xac, 0x02]);
+        assert_eq!(encode_varint(u64::MAX).len(), 10);
+    }
+
+    #[test]
+    fn varints_stop_at_the_first_byte_without_continuation() {
+        assert_eq!(read_varint(&[0x05, 0xff, 0xff]), Some((5, 1)));
+    }
+
+    #[test]
+    fn truncated_varints_are_rejected() {
+        assert_eq!(read_varint(&[]), None);
+        assert_eq!(read_varint(&[0x80]), None);
+        assert_eq!(read_varint(&[0xff, 0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn oversized_varints_are_rejected() {
+        // Eleven bytes.
+        let mut long = vec![0xff; 10];
+        long.push(0x01);
+        assert_eq!(read_varint(&long), None);
+        // Ten bytes, but more than 64 bits.
+        let mut wide = vec![0xff; 9];
+        wide.push(0x02);
+        assert_eq!(read_varint(&wide), None);
+    }
+
+    #[test]
+    fn frames_must_cover_the_buffer_exactly() {
+        let a = login_event("a");
+        let b = login_event("b");
+        let both = delimit(&[&a, &b]);
+        assert_eq!(frames(&both), Some(vec![&a[..], &b[..]]));
+        assert_eq!(frames(&both[..both.len() - 1]), None);
+        assert_eq!(frames(&[]), Some(vec![]));
+    }
+
+    #[test]
+    fn fields_of_a_message() {
+        let msg = login_event("e-1");
+        let parsed = fields(&msg).unwrap();
+        let numbers: Vec<u32> = parsed.iter().map(|f| f.number).collect();
+        assert_eq!(numbers, [1, 2, 3, 6, 8]);
+        assert_eq!(parsed[0].value, b"e-1");
+        assert_eq!(parsed[3].wire_type, WIRE_VARINT);
+        assert!(fields(&msg[..msg.len() - 1]).is_none());
+        // Field number 0 is invalid.
+        assert!(!is_well_formed(&[0x02, 0x00]));
+        // Groups are not supported.
+        assert!(!is_well_formed(&[0x0b]));
+    }
+
+    #[test]
+    fn auto_detects_bare_messages() {
+        let msg = login_event("e-1");
+        let framed = frame(Bytes::from(msg.clone()), Framing::Auto).unwrap();
+        assert!(framed.is_single());
+        assert_eq!(&framed.prefix[..], &encode_varint(msg.len() as u64)[..]);
+        assert_eq!(framed.messages(), vec![&msg[..]]);
+        assert_eq!(framed.len(), msg.len() + framed.prefix.len());
+    }
+
+    #[test]
+    fn auto_detects_delimited_payloads() {
+        let a = login_event("e-1");
+        let b = login_event("e-2");
+        for payload in [delimit(&[&a]), delimit(&[&a, &b])] {
+            let framed = frame(Bytes::from(payload.clone()), Framing::Auto).unwrap();
+            assert!(!framed.is_single());
+            assert_eq!(&framed.payload[..], &payload[..]);
+        }
+        let framed = frame(Bytes::from(delimit(&[&a, &b])), Framing::Auto).unwrap();
+        assert_eq!(framed.messages(), vec![&a[..], &b[..]]);
+        assert_eq!(framed.count, 2);
+    }
+
+    #[test]
+    fn explicit_framing_is_enforced() {
+        let msg = login_event("e-1");
+        let delimited = delimit(&[&msg]);
+        assert_eq!(
+            frame(Bytes::from(msg.clone()), Framing::Delimited).unwrap_err(),
+            FramingError::NotDelimited
+        );
+        assert!(frame(Bytes::from(delimited.clone()), Framing::Delimited).is_ok());
+        assert!(
+            frame(Bytes::from(msg), Framing::Single)
+                .unwrap()
+                .is_single()
+        );
+        assert_eq!(
+            frame(Bytes::from_static(&[0x0a, 0x05, b'a']), Framing::Single).unwrap_err(),
+            FramingError::NotMessage
+        );
+    }
+
+    #[test]
+    fn garbage_is_unrecognized() {
+        assert_eq!(
+            frame(Bytes::from_static(&[0xff, 0xff]), Framing::Auto).unwrap_err(),
+            FramingError::Unrecognized
+        );
+    }
+
+    #[test]
+    fn empty_payloads() {
+        // An empty message is valid protobuf: every field has its default.
+        let framed = frame(Bytes::new(), Framing::Auto).unwrap();
+        assert!(framed.is_single());
+        assert_eq!(&framed.prefix[..], &[0]);
+        assert!(frame(Bytes::new(), Framing::Single).unwrap().is_single());
+        // But it holds no length-delimited messages.
+        assert_eq!(
+            frame(Bytes::new(), Framing::Delimited).unwrap_err(),
+            FramingError::NotDelimited
+        );
+    }
+
+    fn required() -> Vec<String> {
+        vec!["event_id".to_string(), "timestamp".to_string()]
+    }
+
+    fn login_schema() -> MessageSchema {
+        let file = dto();
+        let message = file.message("LoginEvent").unwrap();
+        MessageSchema::new(&file, "LoginEvent", message, &required())
+    }
+
+    #[test]
+    fn valid_messages_pass() {
+        assert_eq!(login_schema().validate(&login_event("e-1")), Ok(()));
+    }
+
+    #[test]
+    fn required_fields_must_be_present_and_non_empty() {
+        let schema = login_schema();
+        let missing_id = encode(&[(6, Value::Int(1))]);
+        let empty_id = encode(&[(1, Value::Str("")), (6, Value::Int(1))]);
+        let missing_ts = encode(&[(1, Value::Str("e-1"))]);
+        let zero_ts = encode(&[(1, Value::Str("e-1")), (6, Value::Int(0))]);
+        for (payload, field) in [
+            (missing_id, "event_id"),
+            (empty_id, "event_id"),
+            (missing_ts, "timestamp"),
+            (zero_ts, "timestamp"),
+        ] {
+            assert_eq!(
+                schema.validate(&payload),
+                Err(PayloadError::MissingField {
+                    message: "LoginEvent".to_string(),
+                    field: field.to_string(),
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn required_fields_only_apply_to_messages_declaring_them() {
+        let file = parse_proto("message Ping { string note = 1; }").unwrap();
+        let schema = MessageSchema::new(&file, "Ping", file.message("Ping").unwrap(), &required());
+        assert_eq!(schema.validate(&[]), Ok(()));
+    }
+
+    #[test]
+    fn wrong_wire_types_are_rejected() {
+        let schema = login_schema();
+        // event_id as a varint, timestamp as a string.
+        let id_as_int = encode(&[(1, Value::Int(7)), (6, Value::Int(1))]);
+        let ts_as_str = encode(&[(1, Value::Str("e-1")), (6, Value::Str("now"))]);
+        assert_eq!(
+            schema.validate(&id_as_int),
+            Err(PayloadError::WireType {
+                message: "LoginEvent".to_string(),
+                field: "event_id".to_string(),
+                wire_type: WIRE_VARINT,
+            })
+        );
+        assert_eq!(
+            schema.validate(&ts_as_str),
+            Err(PayloadError::WireType {
+                message: "LoginEvent".to_string(),
+                field: "timestamp".to_string(),
+                wire_type: WIRE_LEN,
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let msg = login_event("e-1");
+        assert_eq!(
+            login_schema().validate(&msg[..msg.len() - 1]),
+            Err(PayloadError::Malformed("LoginEvent".to_string()))
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_allowed() {
+        let mut msg = login_event("e-1");
+        msg.extend(encode(&[(99, Value::Str("from a newer producer"))]));
+        assert_eq!(login_schema().validate(&msg), Ok(()));
+    }
+
+    #[test]
+    fn repeated_scalars_may_be_packed() {
+        let file = parse_proto(
+            "enum Kind { A = 0; B = 1; }
+             message Batch {
+               repeated int64 ids = 1;
+               repeated string tags = 2;
+               Kind kind = 3;
+               repeated fixed32 codes = 4;
+             }",
+        )
+        .unwrap();
+        let schema = MessageSchema::new(&file, "Batch", file.message("Batch").unwrap(), &[]);
+        let unpacked = encode(&[(1, Value::Int(1)), (1, Value::Int(2)), (3, Value::Int(1))]);
+        let mut packed = encode_varint(1 << 3 | 2);
+        packed.extend([2, 1, 2]);
+        let mut packed_fixed = encode_varint(4 << 3 | 2);
+        packed_fixed.extend([4, 1, 0, 0, 0]);
+        assert_eq!(schema.validate(&unpacked), Ok(()));
+        assert_eq!(schema.validate(&packed), Ok(()));
+        assert_eq!(schema.validate(&packed_fixed), Ok(()));
+        // Strings are never packed, so a varint cannot be one of them.
+        assert!(schema.validate(&encode(&[(2, Value::Int(1))])).is_err());
+        // Enums are varints.
+        assert!(schema.validate(&encode(&[(3, Value::Str("B"))])).is_err());
+    }
+
+    #[test]
+    fn repeated_strings_in_dto_messages() {
+        let file = dto();
+        let message = file.message("ShahreFarangItemEvent").unwrap();
+        let schema = MessageSchema::new(&file, "ShahreFarangItemEvent", message, &required());
+        let genres = message.fields.iter().find(|f| f.name == "genres").unwrap();
+        let mut msg = encode(&[(1, Value::Str("e-1")), (6, Value::Int(1))]);
+        msg.extend(encode(&[
+            (genres.number, Value::Str("drama")),
+            (genres.number, Value::Str("comedy")),
+        ]));
+        assert_eq!(schema.validate(&msg), Ok(()));
+    }
+
+    #[test]
+    fn auto_falls_back_to_a_bare_message_that_validates() {
+        // A LoginEvent whose bytes also split into two well-formed frames:
+        // the first reads as varint fields 1 to 5, the second as field 8.
+        let msg = encode(&[
+            (1, Value::Str("a\x10\x01\x18\x01\x20\x01\x28")),
+            (6, Value::Int(5)),
+            (8, Value::Str("abc")),
+        ]);
+        let payload = Bytes::from(msg.clone());
+        let framed = frame(payload.clone(), Framing::Auto).unwrap();
+        assert!(!framed.is_single());
+        assert_eq!(framed.count, 2);
+
+        let schema = login_schema();
+        let framed = frame_validated(payload.clone(), Framing::Auto, &schema).unwrap();
+        assert!(framed.is_single());
+        assert_eq!(framed.messages(), vec![&msg[..]]);
+        // Explicit framing does not fall back.
+        assert!(matches!(
+            frame_validated(payload, Framing::Delimited, &schema),
+            Err(Rejection::Payload(PayloadError::WireType { .. }))
+        ));
+    }
+
+    #[test]
+    fn validation_failures_of_both_readings_are_rejected() {
+        let schema = login_schema();
+        let missing = encode(&[(1, Value::Str("e-1"))]);
+        assert!(matches!(
+            frame_validated(Bytes::from(missing), Framing::Auto, &schema),
+            Err(Rejection::Payload(PayloadError::MissingField { .. }))
+        ));
+        assert_eq!(
+            frame_validated(Bytes::from_static(&[0xff, 0xff]), Framing::Auto, &schema).unwrap_err(),
+            Rejection::Framing(FramingError::Unrecognized)
+        );
+    }
+}
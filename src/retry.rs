@@ -0,0 +1,54 @@
+use crate::config::RetryConfig;
+use rand::Rng;
+use std::time::Duration;
+
+/// Backoff schedule for in-process insert retries and for the redelivery
+/// delay requested when NAK-ing.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+    nak_base_delay: Duration,
+    nak_max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(retry_config: RetryConfig) -> Self {
+        Self {
+            max_attempts: retry_config.max_attempts.max(1),
+            base_delay: Duration::from_millis(retry_config.base_delay_ms),
+            max_delay: Duration::from_millis(retry_config.max_delay_ms),
+            jitter: retry_config.jitter.clamp(0.0, 1.0),
+            nak_base_delay: Duration::from_millis(retry_config.nak_base_delay_ms),
+            nak_max_delay: Duration::from_millis(retry_config.nak_max_delay_ms),
+        }
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Delay before retry number `attempt` (1-based): `base * 2^(attempt-1)`,
+    /// capped at `max_delay`, then randomized by ±`jitter`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = exponential(self.base_delay, attempt, self.max_delay);
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        let factor = rand::thread_rng().gen_range(1.0 - self.jitter..=1.0 + self.jitter);
+        delay.mul_f64(factor)
+    }
+
+    /// Redelivery delay for a message that has been delivered `delivered` times.
+    pub fn nak_delay(&self, delivered: i64) -> Duration {
+        let delivered = delivered.clamp(1, u32::MAX as i64) as u32;
+        exponential(self.nak_base_delay, delivered, self.nak_max_delay)
+    }
+}
+
+fn exponential(base: Duration, attempt: u32, max: Duration) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    base.saturating_mul(factor).min(max)
+}
@@ -0,0 +1,727 @@
+use crate::click_house::ClickHouseClient;
+use crate::config;
+use crate::protobuf::MessageSchema;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// A `message` declaration parsed from a `.proto` file.
+#[derive(Debug, Clone)]
+pub struct ProtoMessage {
+    pub fields: Vec<ProtoField>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProtoField {
+    pub name: String,
+    pub number: u32,
+    pub ty: String,
+    pub repeated: bool,
+}
+
+/// The subset of a `.proto` file that matters for ingestion: message
+/// declarations (nested ones as `Outer.Inner`) and enum names.
+#[derive(Debug, Default)]
+pub struct ProtoFile {
+    pub package: Option<String>,
+    pub messages: HashMap<String, ProtoMessage>,
+    pub enums: Vec<String>,
+}
+
+impl ProtoFile {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let src = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("read {}: {}", path.display(), e))?;
+        parse_proto(&src).map_err(|e| anyhow::anyhow!("parse {}: {}", path.display(), e))
+    }
+
+    /// Looks up a message by its bare or package-qualified name.
+    pub fn message(&self, name: &str) -> Option<&ProtoMessage> {
+        let name = match &self.package {
+            Some(pkg) => name
+                .strip_prefix(pkg.as_str())
+                .and_then(|n| n.strip_prefix('.'))
+                .unwrap_or(name),
+            None => name,
+        };
+        self.messages.get(name)
+    }
+
+    pub fn is_enum(&self, ty: &str) -> bool {
+        self.enums
+            .iter()
+            .any(|e| e == ty || e.ends_with(&format!(".{ty}")))
+    }
+}
+
+/// The `dto.proto` shipped with the repo, for tests.
+#[cfg(test)]
+pub fn dto() -> ProtoFile {
+    ProtoFile::load(Path::new("build/format_schemas/dto.proto")).unwrap()
+}
+
+/// The `.proto` files named by protobuf routes, each read and parsed once
+/// and shared by the schema check and payload validation. A file that
+/// cannot be loaded keeps its error for the routes naming it.
+#[derive(Default)]
+pub struct ProtoFiles {
+    files: HashMap<String, Result<ProtoFile, String>>,
+}
+
+impl ProtoFiles {
+    pub fn load(routes: &[config::RouteConfig], dir: &Path) -> Self {
+        let mut files = HashMap::new();
+        for route in routes {
+            if route.format != config::InputFormat::Protobuf {
+                continue;
+            }
+            if let Some((file_name, _)) = route.format_schema.split_once(':') {
+                files.entry(file_name.to_string()).or_insert_with(|| {
+                    ProtoFile::load(&dir.join(file_name)).map_err(|e| e.to_string())
+                });
+            }
+        }
+        Self { files }
+    }
+
+    /// The file, message name and message `format_schema`
+    /// ("file.proto:Message") names.
+    pub fn lookup<'a>(
+        &'a self,
+        format_schema: &'a str,
+    ) -> Result<(&'a ProtoFile, &'a str, &'a ProtoMessage), String> {
+        let (file_name, message_name) = format_schema
+            .split_once(':')
+            .ok_or_else(|| format!("`{}` is not of the form file.proto:Message", format_schema))?;
+        let file = match self.files.get(file_name) {
+            Some(Ok(file)) => file,
+            Some(Err(e)) => return Err(e.clone()),
+            None => return Err(format!("{} was not loaded", file_name)),
+        };
+        let message = file
+            .message(message_name)
+            .ok_or_else(|| format!("message `{}` not found in {}", message_name, file_name))?;
+        Ok((file, message_name, message))
+    }
+}
+
+fn tokenize(src: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            '"' | '\'' => {
+                let mut s = String::new();
+                for n in chars.by_ref() {
+                    if n == c {
+                        break;
+                    }
+                    s.push(n);
+                }
+                tokens.push(format!("\"{s}\""));
+            }
+            '{' | '}' | ';' | '=' | '[' | ']' | '<' | '>' | ',' | '(' | ')' => {
+                tokens.push(c.to_string())
+            }
+            _ => {
+                let mut s = c.to_string();
+                while let Some(&n) = chars.peek() {
+                    if n.is_alphanumeric() || n == '_' || n == '.' || n == '-' {
+                        s.push(n);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(s);
+            }
+        }
+    }
+    tokens
+}
+
+pub fn parse_proto(src: &str) -> Result<ProtoFile, anyhow::Error> {
+    let tokens = tokenize(src);
+    let mut file = ProtoFile::default();
+    let mut pos = 0;
+    parse_body(&tokens, &mut pos, "", &mut file, true)?;
+    Ok(file)
+}
+
+fn expect(tokens: &[String], pos: &mut usize, want: &str) -> Result<(), anyhow::Error> {
+    match tokens.get(*pos) {
+        Some(t) if t == want => {
+            *pos += 1;
+            Ok(())
+        }
+        Some(t) => Err(anyhow::anyhow!("expected `{}`, found `{}`", want, t)),
+        None => Err(anyhow::anyhow!("expected `{}`, found end of file", want)),
+    }
+}
+
+fn next<'a>(tokens: &'a [String], pos: &mut usize) -> Result<&'a str, anyhow::Error> {
+    let t = tokens
+        .get(*pos)
+        .ok_or_else(|| anyhow::anyhow!("unexpected end of file"))?;
+    *pos += 1;
+    Ok(t)
+}
+
+/// Skips to the end of the current statement or balanced block.
+fn skip_statement(tokens: &[String], pos: &mut usize) {
+    let mut depth = 0;
+    while let Some(t) = tokens.get(*pos) {
+        *pos += 1;
+        match t.as_str() {
+            "{" => depth += 1,
+            "}" => {
+                depth -= 1;
+                if depth <= 0 {
+                    return;
+                }
+            }
+            ";" if depth == 0 => return,
+            _ => {}
+        }
+    }
+}
+
+/// Parses declarations until the closing `}` of the enclosing message (or
+/// end of file at top level), collecting fields into the returned list.
+fn parse_body(
+    tokens: &[String],
+    pos: &mut usize,
+    scope: &str,
+    file: &mut ProtoFile,
+    top_level: bool,
+) -> Result<Vec<ProtoField>, anyhow::Error> {
+    let mut fields = Vec::new();
+    while let Some(tok) = tokens.get(*pos) {
+        match tok.as_str() {
+            "}" if !top_level => {
+                *pos += 1;
+                return Ok(fields);
+            }
+            ";" => *pos += 1,
+            "package" if top_level => {
+                *pos += 1;
+                file.package = Some(next(tokens, pos)?.to_string());
+                expect(tokens, pos, ";")?;
+            }
+            "syntax" | "import" | "option" | "reserved" | "extensions" | "service" | "extend" => {
+                skip_statement(tokens, pos)
+            }
+            "message" => {
+                *pos += 1;
+                let name = format!("{}{}", scope, next(tokens, pos)?);
+                expect(tokens, pos, "{")?;
+                let fields = parse_body(tokens, pos, &format!("{name}."), file, false)?;
+                file.messages.insert(name, ProtoMessage { fields });
+            }
+            "enum" => {
+                *pos += 1;
+                file.enums.push(format!("{}{}", scope, next(tokens, pos)?));
+                skip_statement(tokens, pos);
+            }
+            "oneof" if !top_level => {
+                *pos += 1;
+                next(tokens, pos)?;
+                expect(tokens, pos, "{")?;
+                fields.extend(parse_body(tokens, pos, scope, file, false)?);
+            }
+            _ if !top_level => fields.push(parse_field(tokens, pos)?),
+            other => return Err(anyhow::anyhow!("unexpected `{}` at top level", other)),
+        }
+    }
+    if top_level {
+        Ok(fields)
+    } else {
+        Err(anyhow::anyhow!("unterminated message block"))
+    }
+}
+
+fn parse_field(tokens: &[String], pos: &mut usize) -> Result<ProtoField, anyhow::Error> {
+    let mut repeated = false;
+    let mut ty = next(tokens, pos)?.to_string();
+    if matches!(ty.as_str(), "repeated" | "optional" | "required") {
+        repeated = ty == "repeated";
+        ty = next(tokens, pos)?.to_string();
+    }
+    if ty == "map" {
+        // map<K, V> is sent as a repeated entry message.
+        let mut inner = String::from("map");
+        loop {
+            let t = next(tokens, pos)?;
+            inner.push_str(t);
+            if t == ">" {
+                break;
+            }
+        }
+        ty = inner;
+        repeated = true;
+    }
+    let name = next(tokens, pos)?.to_string();
+    expect(tokens, pos, "=")?;
+    let number = next(tokens, pos)?
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid field number for `{}`", name))?;
+    if tokens.get(*pos).map(String::as_str) == Some("[") {
+        while next(tokens, pos)? != "]" {}
+    }
+    expect(tokens, pos, ";")?;
+    Ok(ProtoField {
+        name,
+        number,
+        ty,
+        repeated,
+    })
+}
+
+/// Strips wrappers that don't change how a value is parsed.
+fn unwrap_ch_type(ty: &str) -> &str {
+    let mut ty = ty.trim();
+    for wrapper in ["Nullable(", "LowCardinality("] {
+        if let Some(inner) = ty.strip_prefix(wrapper).and_then(|t| t.strip_suffix(')')) {
+            ty = unwrap_ch_type(inner);
+        }
+    }
+    ty
+}
+
+fn ch_type_compatible(file: &ProtoFile, field: &ProtoField, ch_type: &str) -> bool {
+    let ch_type = unwrap_ch_type(ch_type);
+    if field.repeated {
+        if field.ty.starts_with("map<") {
+            return ch_type.starts_with("Map(") || ch_type.starts_with("Array(");
+        }
+        return match ch_type
+            .strip_prefix("Array(")
+            .and_then(|t| t.strip_suffix(')'))
+        {
+            Some(inner) => ch_type_compatible(
+                file,
+                &ProtoField {
+                    repeated: false,
+                    ..field.clone()
+                },
+                inner,
+            ),
+            None => false,
+        };
+    }
+
+    let family = ch_type.split('(').next().unwrap_or(ch_type);
+    let is_int = family.starts_with("Int") || family.starts_with("UInt");
+    let is_float = family.starts_with("Float") || family.starts_with("Decimal");
+    let is_time = family.starts_with("Date");
+    let is_enum = family.starts_with("Enum");
+
+    match field.ty.as_str() {
+        "string" | "bytes" => {
+            matches!(family, "String" | "FixedString" | "UUID" | "IPv4" | "IPv6")
+                || is_time
+                || is_enum
+        }
+        "int32" | "int64" | "uint32" | "uint64" | "sint32" | "sint64" | "fixed32" | "fixed64"
+        | "sfixed32" | "sfixed64" => is_int || is_float || is_time || is_enum || family == "Bool",
+        "double" | "float" => is_float,
+        "bool" => family == "Bool" || is_int,
+        ty if file.is_enum(ty) => is_enum || is_int || family == "String",
+        // Nested messages map onto Tuple/Nested columns; leave those to ClickHouse.
+        _ => !is_int && !is_float && !is_time,
+    }
+}
+
+#[derive(Debug)]
+pub enum SchemaProblem {
+    Proto(String),
+    MissingTable,
+    MissingColumn {
+        field: String,
+        number: u32,
+        ty: String,
+    },
+    TypeMismatch {
+        column: String,
+        ch_type: String,
+        proto_type: String,
+    },
+}
+
+impl fmt::Display for SchemaProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaProblem::Proto(e) => write!(f, "format schema: {}", e),
+            SchemaProblem::MissingTable => write!(f, "table does not exist"),
+            SchemaProblem::MissingColumn { field, number, ty } => write!(
+                f,
+                "missing column for proto field `{}` = {} ({})",
+                field, number, ty
+            ),
+            SchemaProblem::TypeMismatch {
+                column,
+                ch_type,
+                proto_type,
+            } => write!(
+                f,
+                "column `{}` is {} but proto field is {}",
+                column, ch_type, proto_type
+            ),
+        }
+    }
+}
+
+/// Compares a message definition against the live table columns.
+fn compare(
+    file: &ProtoFile,
+    message: &ProtoMessage,
+    columns: &[(String, String)],
+) -> Vec<SchemaProblem> {
+    let columns: HashMap<String, &str> = columns
+        .iter()
+        .map(|(name, ty)| (name.to_lowercase(), ty.as_str()))
+        .collect();
+
+    let mut problems = Vec::new();
+    for field in &message.fields {
+        let proto_type = if field.repeated && !field.ty.starts_with("map<") {
+            format!("repeated {}", field.ty)
+        } else {
+            field.ty.clone()
+        };
+        match columns.get(&field.name.to_lowercase()) {
+            None => problems.push(SchemaProblem::MissingColumn {
+                field: field.name.clone(),
+                number: field.number,
+                ty: proto_type,
+            }),
+            Some(ch_type) if !ch_type_compatible(file, field, ch_type) => {
+                problems.push(SchemaProblem::TypeMismatch {
+                    column: field.name.clone(),
+                    ch_type: ch_type.to_string(),
+                    proto_type,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    problems
+}
+
+/// Checks every route against its ClickHouse table and, for protobuf
+/// routes, its `.proto` message.
+/// Returns the routes that passed; in `fail` mode any problem is an error.
+pub async fn validate_routes(
+    ch: &ClickHouseClient,
+    routes: Vec<config::RouteConfig>,
+    check: &config::SchemaCheckConfig,
+    default_db: &str,
+    protos: &ProtoFiles,
+) -> Result<Vec<config::RouteConfig>, anyhow::Error> {
+    if !check.enabled {
+        return Ok(routes);
+    }
+
+    let mut passed = Vec::with_capacity(routes.len());
+    let mut failed = 0;
+
+    for route in routes {
+        let database = route.database.as_deref().unwrap_or(default_db);
+        let mut problems = Vec::new();
+
+        // Only protobuf routes have a message to compare the table with.
+        let message = if route.format == config::InputFormat::Protobuf {
+            protos
+                .lookup(&route.format_schema)
+                .map(|(file, _, message)| (file, message))
+                .map_err(|e| problems.push(SchemaProblem::Proto(e)))
+                .ok()
+        } else {
+            None
+        };
+
+        let columns = ch.table_columns(database, &route.table).await?;
+        if columns.is_empty() {
+            problems.push(SchemaProblem::MissingTable);
+        } else if let Some((file, message)) = message {
+            problems.extend(compare(file, message, &columns));
+        }
+
+        if problems.is_empty() {
+            passed.push(route);
+            continue;
+        }
+
+        failed += 1;
+        for p in &problems {
+            error!(
+                "route {} -> {}.{}: {}",
+                route.subject, database, route.table, p
+            );
+        }
+        if check.on_mismatch == config::SchemaMismatchAction::Disable {
+            warn!(
+                "route {} disabled; its messages will be treated as unrouted",
+                route.subject
+            );
+        }
+    }
+
+    if failed > 0 && check.on_mismatch == config::SchemaMismatchAction::Fail {
+        return Err(anyhow::anyhow!(
+            "schema validation failed for {} route(s)",
+            failed
+        ));
+    }
+    info!(
+        "Schema validation: {} route(s) ok, {} disabled.",
+        passed.len(),
+        failed
+    );
+    Ok(passed)
+}
+
+/// Builds the payload validator of each route's message, keyed by
+/// `format_schema`. Empty when payload validation is disabled.
+pub fn message_schemas(
+    routes: &[config::RouteConfig],
+    validation: &config::PayloadValidationConfig,
+    protos: &ProtoFiles,
+) -> Result<HashMap<String, Arc<MessageSchema>>, anyhow::Error> {
+    let mut schemas = HashMap::new();
+    if !validation.enabled {
+        return Ok(schemas);
+    }
+    for route in routes {
+        if route.format != config::InputFormat::Protobuf
+            || schemas.contains_key(&route.format_schema)
+        {
+            continue;
+        }
+        let (file, message_name, message) = protos
+            .lookup(&route.format_schema)
+            .map_err(|e| anyhow::anyhow!(e))?;
+        schemas.insert(
+            route.format_schema.clone(),
+            Arc::new(MessageSchema::new(
+                file,
+                message_name,
+                message,
+                &validation.required_fields,
+            )),
+        );
+    }
+    Ok(schemas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field<'a>(message: &'a ProtoMessage, name: &str) -> &'a ProtoField {
+        message.fields.iter().find(|f| f.name == name).unwrap()
+    }
+
+    #[test]
+    fn parses_dto_proto() {
+        let file = dto();
+        assert_eq!(file.package.as_deref(), Some("dto"));
+        assert_eq!(file.messages.len(), 9);
+        for (name, message) in &file.messages {
+            let event_id = field(message, "event_id");
+            assert_eq!(
+                (event_id.number, event_id.ty.as_str()),
+                (1, "string"),
+                "{}",
+                name
+            );
+            let timestamp = field(message, "timestamp");
+            assert_eq!(
+                (timestamp.number, timestamp.ty.as_str()),
+                (6, "int64"),
+                "{}",
+                name
+            );
+        }
+        let login = file.message("LoginEvent").unwrap();
+        assert_eq!(login.fields.len(), 16);
+        assert!(login.fields.iter().all(|f| !f.repeated));
+        let item = file.message("ShahreFarangItemEvent").unwrap();
+        assert!(field(item, "genres").repeated);
+        assert_eq!(field(item, "genres").ty, "string");
+    }
+
+    #[test]
+    fn proto_files_are_loaded_once_per_file() {
+        let route = |format, format_schema: &str| config::RouteConfig {
+            subject: "events.login".to_string(),
+            table: "login_events".to_string(),
+            format,
+            format_schema: format_schema.to_string(),
+            settings: Default::default(),
+            database: None,
+            max_latency_ms: None,
+            framing: config::Framing::Auto,
+        };
+        let protos = ProtoFiles::load(
+            &[
+                route(config::InputFormat::Protobuf, "dto.proto:LoginEvent"),
+                route(config::InputFormat::Protobuf, "dto.proto:SessionEvent"),
+                route(config::InputFormat::Protobuf, "missing.proto:LoginEvent"),
+                route(config::InputFormat::JsonEachRow, ""),
+            ],
+            Path::new("build/format_schemas"),
+        );
+        assert_eq!(protos.files.len(), 2);
+        let (_, name, message) = protos.lookup("dto.proto:dto.LoginEvent").unwrap();
+        assert_eq!(name, "dto.LoginEvent");
+        assert_eq!(message.fields.len(), 16);
+        let err = |format_schema| protos.lookup(format_schema).unwrap_err();
+        assert!(err("dto.proto").contains("is not of the form"));
+        assert!(err("dto.proto:Missing").contains("message `Missing` not found"));
+        assert!(err("missing.proto:LoginEvent").starts_with("read "));
+        assert!(err("other.proto:LoginEvent").contains("was not loaded"));
+    }
+
+    #[test]
+    fn messages_are_found_by_bare_or_qualified_name() {
+        let file = dto();
+        assert!(file.message("LoginEvent").is_some());
+        assert!(file.message("dto.LoginEvent").is_some());
+        assert!(file.message("other.LoginEvent").is_none());
+        assert!(file.message("Missing").is_none());
+    }
+
+    #[test]
+    fn parses_nested_declarations() {
+        let file = parse_proto(
+            r#"
+            syntax = "proto3";
+            import "google/protobuf/timestamp.proto";
+            option go_package = "x/y";
+            /* a block comment */
+            message Outer {
+              enum Kind { A = 0; B = 1; }
+              message Inner { string v = 1; }
+              Kind kind = 1; // trailing comment
+              Inner inner = 2;
+              map<string, int64> counts = 3;
+              oneof choice {
+                string name = 4;
+                int32 id = 5;
+              }
+              repeated uint32 ids = 6 [packed = true];
+              reserved 7, 8;
+            }
+            "#,
+        )
+        .unwrap();
+        let outer = file.message("Outer").unwrap();
+        let names: Vec<&str> = outer.fields.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["kind", "inner", "counts", "name", "id", "ids"]);
+        assert!(file.message("Outer.Inner").is_some());
+        assert!(file.is_enum("Kind"));
+        assert!(file.is_enum("Outer.Kind"));
+        let counts = field(outer, "counts");
+        assert!(counts.repeated);
+        assert_eq!(counts.ty, "map<string,int64>");
+        assert!(field(outer, "ids").repeated);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(parse_proto("message A { string a = 1; ").is_err());
+        assert!(parse_proto("message A { string a = x; }").is_err());
+        assert!(parse_proto("message A { string a 1; }").is_err());
+        assert!(parse_proto("string a = 1;").is_err());
+    }
+
+    fn proto_field(ty: &str, repeated: bool) -> ProtoField {
+        ProtoField {
+            name: "f".to_string(),
+            number: 1,
+            ty: ty.to_string(),
+            repeated,
+        }
+    }
+
+    #[test]
+    fn ch_type_compatibility() {
+        let file = parse_proto("enum Kind { A = 0; }").unwrap();
+        let ok = |ty: &str, repeated: bool, ch: &str| {
+            ch_type_compatible(&file, &proto_field(ty, repeated), ch)
+        };
+        assert!(ok("string", false, "String"));
+        assert!(ok("string", false, "LowCardinality(String)"));
+        assert!(ok("string", false, "Nullable(String)"));
+        assert!(ok("string", false, "DateTime64(3)"));
+        assert!(!ok("string", false, "UInt64"));
+        assert!(ok("int64", false, "Int64"));
+        assert!(ok("int64", false, "DateTime64(3)"));
+        assert!(!ok("int64", false, "String"));
+        assert!(ok("double", false, "Float64"));
+        assert!(!ok("double", false, "Int32"));
+        assert!(ok("bool", false, "Bool"));
+        assert!(ok("bool", false, "UInt8"));
+        assert!(ok("Kind", false, "Enum8('A' = 0)"));
+        assert!(ok("Kind", false, "String"));
+        assert!(ok("string", true, "Array(String)"));
+        assert!(ok("string", true, "Array(LowCardinality(String))"));
+        assert!(!ok("string", true, "String"));
+        assert!(!ok("string", false, "Array(String)"));
+        assert!(ok("map<string,int64>", true, "Map(String, Int64)"));
+    }
+
+    #[test]
+    fn compare_reports_missing_and_mismatched_columns() {
+        let file = dto();
+        let login = file.message("LoginEvent").unwrap();
+        let mut columns: Vec<(String, String)> = login
+            .fields
+            .iter()
+            .map(|f| {
+                let ty = if f.ty == "int64" { "Int64" } else { "String" };
+                (f.name.clone(), ty.to_string())
+            })
+            .collect();
+        assert!(compare(&file, login, &columns).is_empty());
+
+        columns.retain(|(name, _)| name != "user_agent");
+        columns
+            .iter_mut()
+            .find(|(name, _)| name == "timestamp")
+            .unwrap()
+            .1 = "String".to_string();
+        let problems: Vec<String> = compare(&file, login, &columns)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "column `timestamp` is String but proto field is int64",
+                "missing column for proto field `user_agent` = 16 (string)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use crate::config;
+use crate::health::Health;
+use crate::metrics::METRICS;
+use axum::Router;
+use axum::extract::State;
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Binds the monitoring listener, so a taken port fails startup rather than
+/// leaving the service running without probes.
+pub async fn bind(
+    monitoring_config: &config::MonitoringConfig,
+) -> Result<TcpListener, std::io::Error> {
+    let listener =
+        TcpListener::bind((monitoring_config.host.as_str(), monitoring_config.port)).await?;
+    info!("Monitoring endpoint: http://{}/", listener.local_addr()?);
+    Ok(listener)
+}
+
+/// Serves the monitoring endpoints until `shutdown` is cancelled.
+pub async fn serve(
+    listener: TcpListener,
+    health: Arc<Health>,
+    shutdown: CancellationToken,
+) -> Result<(), std::io::Error> {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(health);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.encode(),
+    )
+}
+
+fn check_response(failed: Vec<&'static str>) -> (StatusCode, String) {
+    if failed.is_empty() {
+        (StatusCode::OK, "ok\n".to_string())
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, failed.join("\n") + "\n")
+    }
+}
+
+async fn healthz(State(health): State<Arc<Health>>) -> impl IntoResponse {
+    check_response(health.liveness())
+}
+
+async fn readyz(State(health): State<Arc<Health>>) -> impl IntoResponse {
+    check_response(health.readiness())
+}